@@ -0,0 +1,194 @@
+//! Just enough ACPI to find the interrupt controllers.
+//!
+//! The RSDP is found by scanning the BIOS areas for its signature. It points
+//! to the RSDT, or the XSDT from ACPI 2.0 on, which lists the other tables;
+//! the only one read is the MADT, signature `APIC`, describing the local
+//! APIC, the I/O APICs and how ISA interrupts are wired to them.
+//!
+//! All tables are read through the physical memory mapping.
+
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use core::{ptr, slice};
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// Length of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    NoMadt,
+    BadChecksum,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt that isn't identity mapped to a global system
+/// interrupt, or doesn't use the ISA polarity and trigger mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// What the MADT says about the interrupt controllers.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    /// The legacy 8259 PICs are present and need to be masked.
+    pub has_legacy_pics: bool,
+    /// Ids of the usable processors' local APICs.
+    pub processors: Vec<u8>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Global system interrupt, polarity and trigger mode of an ISA IRQ.
+    pub fn isa_interrupt(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|entry| entry.isa_irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                isa_irq: irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+/// Bytes of physical memory at `addr`.
+///
+/// Unsafe because the range must be memory the firmware handed over, not
+/// device registers.
+unsafe fn physical(addr: u64, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(PhysAddr::new(addr)).as_ptr(), len)
+}
+
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> T {
+    assert!(offset + core::mem::size_of::<T>() <= bytes.len());
+    unsafe { ptr::read_unaligned(bytes[offset..].as_ptr() as *const T) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Finds the RSDP in the first KiB of the EBDA or in the BIOS ROM, where it
+/// is 16-byte aligned.
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(read::<u16>(unsafe { physical(0x40e, 2) }, 0)) << 4;
+    let areas = [(ebda, 1024), (0xe0000, 0x20000)];
+    areas
+        .iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, len)| (start..start + len).step_by(16))
+        .find(|&addr| {
+            let rsdp = unsafe { physical(addr, 20) };
+            &rsdp[..8] == RSDP_SIGNATURE && checksum_ok(rsdp)
+        })
+}
+
+/// The whole table at `addr`, once its checksum has been checked.
+fn table(addr: u64) -> Result<&'static [u8], AcpiError> {
+    let len = read::<u32>(unsafe { physical(addr, HEADER_SIZE) }, 4) as usize;
+    let table = unsafe { physical(addr, len.max(HEADER_SIZE)) };
+    if checksum_ok(table) {
+        Ok(table)
+    } else {
+        Err(AcpiError::BadChecksum)
+    }
+}
+
+/// Addresses of the tables listed by the RSDT or XSDT.
+fn tables() -> Result<Vec<u64>, AcpiError> {
+    let rsdp_addr = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    let rsdp = unsafe { physical(rsdp_addr, 36) };
+    let revision = rsdp[15];
+    let (root, entry_size) = if revision >= 2 && checksum_ok(rsdp) {
+        (read::<u64>(rsdp, 24), 8)
+    } else {
+        (u64::from(read::<u32>(rsdp, 16)), 4)
+    };
+    let root = table(root)?;
+    Ok(root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => read::<u64>(entry, 0),
+            _ => u64::from(read::<u32>(entry, 0)),
+        })
+        .collect())
+}
+
+/// Reads the MADT.
+pub fn madt() -> Result<Madt, AcpiError> {
+    let madt = tables()?
+        .into_iter()
+        .find(|&addr| unsafe { &physical(addr, 4)[..4] } == MADT_SIGNATURE)
+        .ok_or(AcpiError::NoMadt)?;
+    let madt = table(madt)?;
+
+    let mut info = Madt {
+        local_apic: PhysAddr::new(u64::from(read::<u32>(madt, 36))),
+        has_legacy_pics: read::<u32>(madt, 40) & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    // entries start with their type and length
+    let mut offset = 44;
+    while offset + 2 <= madt.len() {
+        let (kind, len) = (madt[offset], madt[offset + 1] as usize);
+        if len < 2 || offset + len > madt.len() {
+            break;
+        }
+        let entry = &madt[offset..offset + len];
+        match kind {
+            // processor local APIC, enabled or online capable
+            0 if len >= 8 && read::<u32>(entry, 4) & 0b11 != 0 => {
+                info.processors.push(entry[3]);
+            }
+            1 if len >= 12 => info.io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysAddr::new(u64::from(read::<u32>(entry, 4))),
+                gsi_base: read::<u32>(entry, 8),
+            }),
+            2 if len >= 10 => {
+                let flags = read::<u16>(entry, 8);
+                info.overrides.push(InterruptOverride {
+                    isa_irq: entry[3],
+                    gsi: read::<u32>(entry, 4),
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            // 64-bit local APIC address
+            5 if len >= 12 => info.local_apic = PhysAddr::new(read::<u64>(entry, 4)),
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(info)
+}
+
+#[test_case]
+fn test_finds_madt() {
+    let madt = madt().expect("QEMU provides a MADT");
+    assert!(!madt.processors.is_empty());
+    assert!(!madt.io_apics.is_empty());
+    // QEMU wires the PIT to GSI 2
+    assert_eq!(madt.isa_interrupt(0).gsi, 2);
+}
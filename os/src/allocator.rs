@@ -1,16 +1,101 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 pub mod bump;
+pub mod debug;
 pub mod fixed_size_block;
 pub mod linked_list;
-use fixed_size_block::FixedSizeBlockAllocator;
+pub mod linked_list_crate;
+pub mod slab;
+
+// The heap allocator is picked with exactly one of the `alloc-*` features,
+// e.g. `cargo test --no-default-features --features alloc-bump`.
+const _: () = assert!(
+    cfg!(feature = "alloc-bump") as u8
+        + cfg!(feature = "alloc-linked-list") as u8
+        + cfg!(feature = "alloc-fixed-block") as u8
+        + cfg!(feature = "alloc-linked-list-crate") as u8
+        == 1,
+    "exactly one of the `alloc-*` features must be enabled"
+);
+
+#[cfg(feature = "alloc-bump")]
+type Allocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type Allocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type Allocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-linked-list-crate")]
+type Allocator = linked_list_crate::CrateLinkedListAllocator;
+
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static ALLOCATOR: KernelHeap<Allocator> = KernelHeap::new(Allocator::new());
 
+// with `heap-debug`, the statistics include the redzones
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: debug::DebugAllocator<KernelHeap<Allocator>> =
+    debug::DebugAllocator::new(KernelHeap::new(Allocator::new()));
+
+#[cfg(not(feature = "heap-debug"))]
+fn kernel_heap() -> &'static KernelHeap<Allocator> {
+    &ALLOCATOR
+}
+
+#[cfg(feature = "heap-debug")]
+fn kernel_heap() -> &'static KernelHeap<Allocator> {
+    ALLOCATOR.inner()
+}
+
+pub const HEAP_START: usize = 0xFFFF_9000_0000_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB, mapped at boot
+/// Default limit the heap may grow to, see `set_heap_limit`.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The heap grows by at least this much at a time.
+const HEAP_GROWTH: usize = 64 * 1024;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::Relaxed)
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Sets the size the heap may grow to. Memory already mapped is kept.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Maps at least `min` more bytes at the end of the heap and returns how
+/// many were added, or `None` if that would exceed the limit or there are no
+/// frames left.
+///
+/// Only called by `KernelHeap`, with the allocator's lock held.
+fn grow_heap(min: usize) -> Option<usize> {
+    let mapped = heap_size();
+    let by = align_up(min.max(HEAP_GROWTH), 4096);
+    let by = by.min(heap_limit().saturating_sub(mapped));
+    if by < min {
+        return None;
+    }
+    let start = VirtAddr::new((HEAP_START + mapped) as u64);
+    let pages = Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + (by - 1)),
+    );
+    unsafe { crate::memory::map_heap_pages(pages) }.ok()?;
+    HEAP_MAPPED.store(mapped + by, Ordering::Relaxed);
+    Some(by)
+}
 
 pub struct Dummy;
 
@@ -52,12 +137,213 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        HeapAllocator::init(&mut *kernel_heap().allocator.lock(), HEAP_START, HEAP_SIZE);
     }
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::Relaxed);
 
     Ok(())
 }
 
+/// Counters kept by each allocator, updated under its lock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllocStats {
+    /// Bytes requested by live allocations.
+    pub allocated: usize,
+    /// Highest value `allocated` has reached.
+    pub peak: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// Times the allocator ran out of memory, including the ones after
+    /// which the heap was grown.
+    pub failures: u64,
+}
+
+impl AllocStats {
+    pub const fn new() -> Self {
+        AllocStats {
+            allocated: 0,
+            peak: 0,
+            allocations: 0,
+            deallocations: 0,
+            failures: 0,
+        }
+    }
+
+    /// Number of allocations not freed yet.
+    pub fn live(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+
+    fn record_alloc(&mut self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() {
+            self.failures += 1;
+        } else {
+            self.allocations += 1;
+            self.allocated += size;
+            self.peak = self.peak.max(self.allocated);
+        }
+    }
+
+    fn record_dealloc(&mut self, size: usize) {
+        self.deallocations += 1;
+        self.allocated -= size;
+    }
+
+    /// Records an allocation resized in place.
+    fn record_resize(&mut self, old_size: usize, new_size: usize) {
+        self.allocated = self.allocated - old_size + new_size;
+        self.peak = self.peak.max(self.allocated);
+    }
+}
+
+/// Size class figures of the fixed-size block allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockStats {
+    pub size_classes: [SizeClass; fixed_size_block::SIZE_CLASSES],
+    /// Allocations too large for any size class.
+    pub fallback_allocations: u64,
+}
+
+/// Statistics of the kernel heap, as returned by `heap_stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Name of the allocator selected at compile time.
+    pub allocator: &'static str,
+    pub alloc: AllocStats,
+    /// Bytes mapped for the heap.
+    pub heap_size: usize,
+    pub heap_limit: usize,
+    /// Bytes of the heap not available to new allocations, including
+    /// allocator overhead and cached blocks.
+    pub used: usize,
+    /// Only for the fixed-size block allocator.
+    pub blocks: Option<BlockStats>,
+}
+
+impl HeapStats {
+    pub fn free(&self) -> usize {
+        self.heap_size.saturating_sub(self.used)
+    }
+
+    /// Bytes in free blocks of the size classes, which can only be reused
+    /// for allocations of the same class.
+    pub fn cached(&self) -> usize {
+        self.blocks.map_or(0, |blocks| {
+            blocks
+                .size_classes
+                .iter()
+                .map(|class| class.free * class.block_size)
+                .sum()
+        })
+    }
+
+    /// Bytes used but not requested, lost to rounding allocations up and to
+    /// the allocator's own bookkeeping.
+    pub fn internal_fragmentation(&self) -> usize {
+        self.used
+            .saturating_sub(self.cached())
+            .saturating_sub(self.alloc.allocated)
+    }
+}
+
+pub use fixed_size_block::SizeClass;
+
+pub fn heap_stats() -> HeapStats {
+    let allocator = kernel_heap().allocator.lock();
+    HeapStats {
+        allocator: Allocator::NAME,
+        alloc: allocator.alloc_stats(),
+        heap_size: heap_size(),
+        heap_limit: heap_limit(),
+        used: allocator.used(),
+        blocks: allocator.block_stats(),
+    }
+}
+
+/// Interface of the allocators that can back the kernel heap.
+pub trait HeapAllocator {
+    /// Name shown by `meminfo`.
+    const NAME: &'static str;
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// Unsafe because the caller must guarantee that the given heap bounds
+    /// are valid and that the heap is unused. Must be called only once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Adds `by` bytes directly after the end of the heap.
+    ///
+    /// Unsafe because the memory must be mapped and unused.
+    unsafe fn extend(&mut self, by: usize);
+
+    fn alloc_stats(&self) -> AllocStats;
+
+    /// Bytes of the heap not available to new allocations.
+    fn used(&self) -> usize;
+
+    fn block_stats(&self) -> Option<BlockStats> {
+        None
+    }
+}
+
+/// The global allocator: a `HeapAllocator` that grows the heap whenever it
+/// runs out of memory.
+pub struct KernelHeap<A> {
+    allocator: Locked<A>,
+}
+
+impl<A> KernelHeap<A> {
+    pub const fn new(allocator: A) -> Self {
+        KernelHeap {
+            allocator: Locked::new(allocator),
+        }
+    }
+}
+
+impl<A: HeapAllocator> KernelHeap<A> {
+    /// Grows the heap by enough for `layout`, returns `false` if it can't.
+    fn grow(&self, layout: Layout) -> bool {
+        let mut allocator = self.allocator.lock();
+        // enough for the allocation even if the free memory at the end of
+        // the heap can't be used for it
+        match grow_heap(layout.size() + layout.align()) {
+            Some(added) => {
+                unsafe { allocator.extend(added) };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for KernelHeap<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.allocator.alloc(layout);
+        if ptr.is_null() && self.grow(layout) {
+            self.allocator.alloc(layout)
+        } else {
+            ptr
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.allocator.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.allocator.realloc(ptr, layout, new_size);
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if new_ptr.is_null() && self.grow(new_layout) {
+            self.allocator.realloc(ptr, layout, new_size)
+        } else {
+            new_ptr
+        }
+    }
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -3,6 +3,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    stats: AllocStats,
 }
 
 impl BumpAllocator {
@@ -13,6 +14,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            stats: AllocStats::new(),
         }
     }
 
@@ -25,9 +27,38 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    /// Bytes that can't be reused until all allocations are freed.
+    pub fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    const NAME: &'static str = "bump";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+    }
+
+    fn alloc_stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    fn used(&self) -> usize {
+        self.used()
+    }
 }
 
-use super::{align_up, Locked};
+use super::{align_up, AllocStats, HeapAllocator, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -41,18 +72,21 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
-        if alloc_end > bump.heap_end {
+        let ptr = if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
             alloc_start as *mut u8
-        }
+        };
+        bump.stats.record_alloc(ptr, layout.size());
+        ptr
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get a mutable reference
 
+        bump.stats.record_dealloc(layout.size());
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
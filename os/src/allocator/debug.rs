@@ -0,0 +1,234 @@
+//! Heap corruption checks, enabled with the `heap-debug` feature.
+//!
+//! `DebugAllocator` wraps each allocation in a header and two redzones:
+//!
+//! ```text
+//! | links | header | front redzone | data | back redzone |
+//! ```
+//!
+//! The first 16 bytes are left to the inner allocator, which keeps its free
+//! list links there, so the header survives a free until the memory is
+//! reused. New allocations are filled with `ALLOC_POISON` and freed ones with
+//! `FREE_POISON`. `dealloc` checks the header and both redzones and panics
+//! with the offending allocation on double frees, layout mismatches and
+//! overflows.
+
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    fmt, mem, ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const ALIVE: u64 = 0xa110_ca7e_d0a1_10c5;
+const FREED: u64 = 0xf2ee_d0f2_ee0d_dead;
+pub const ALLOC_POISON: u8 = 0xcd;
+pub const FREE_POISON: u8 = 0xdd;
+const REDZONE_BYTE: u8 = 0xfb;
+const REDZONE_SIZE: usize = 16;
+/// Room for the inner allocator's free list links.
+const LINKS_SIZE: usize = 16;
+const MIN_ALIGN: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+    /// Sequence number of the allocation, to tell them apart in reports.
+    id: u64,
+}
+
+/// A problem found by `DebugAllocator::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    DoubleFree,
+    /// The header was overwritten, or the pointer was never allocated.
+    BadHeader,
+    /// Freed with a different size or alignment than it was allocated with.
+    LayoutMismatch,
+    /// Bytes before the allocation were overwritten.
+    Underflow,
+    /// Bytes after the allocation were overwritten.
+    Overflow,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Corruption::DoubleFree => "double free",
+            Corruption::BadHeader => "corrupted header or invalid pointer",
+            Corruption::LayoutMismatch => "freed with wrong layout",
+            Corruption::Underflow => "buffer underflow",
+            Corruption::Overflow => "buffer overflow",
+        })
+    }
+}
+
+/// Allocator wrapper adding redzones, poisoning and free checks.
+pub struct DebugAllocator<A> {
+    inner: A,
+    next_id: AtomicU64,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator {
+            inner,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// Offset of the data from the start of the inner allocation.
+fn data_offset(align: usize) -> usize {
+    let before = LINKS_SIZE + mem::size_of::<Header>() + REDZONE_SIZE;
+    (before + align - 1) & !(align - 1)
+}
+
+/// Layout passed to the inner allocator for `layout`.
+fn outer_layout(layout: Layout) -> Option<Layout> {
+    let align = layout.align().max(MIN_ALIGN);
+    let size = data_offset(align)
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    Layout::from_size_align(size, align).ok()
+}
+
+unsafe fn header(data: *mut u8, align: usize) -> *mut Header {
+    data.sub(data_offset(align) - LINKS_SIZE) as *mut Header
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    /// Checks the allocation at `ptr` before it's freed with `layout`.
+    ///
+    /// Unsafe because `ptr` must point into the heap.
+    pub unsafe fn check(&self, ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+        let align = layout.align().max(MIN_ALIGN);
+        let header = header(ptr, align).read();
+        match header.magic {
+            ALIVE => {}
+            FREED => return Err(Corruption::DoubleFree),
+            _ => return Err(Corruption::BadHeader),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            return Err(Corruption::LayoutMismatch);
+        }
+        let front = core::slice::from_raw_parts(ptr.sub(REDZONE_SIZE), REDZONE_SIZE);
+        if front.iter().any(|&byte| byte != REDZONE_BYTE) {
+            return Err(Corruption::Underflow);
+        }
+        let back = core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+        if back.iter().any(|&byte| byte != REDZONE_BYTE) {
+            return Err(Corruption::Overflow);
+        }
+        Ok(())
+    }
+}
+
+/// Prints the corruption to serial and panics with it.
+#[cold]
+fn report(corruption: Corruption, ptr: *mut u8, layout: Layout, header: Header) -> ! {
+    serial_println!(
+        "heap corruption: {} at {:p}, freed with size {} align {}",
+        corruption,
+        ptr,
+        layout.size(),
+        layout.align()
+    );
+    serial_println!("  header: {:x?}", header);
+    panic!(
+        "heap corruption: {} at {:p} (allocation #{} of {} bytes)",
+        corruption, ptr, header.id, header.size
+    );
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let outer = match outer_layout(layout) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(data_offset(outer.align()));
+        header(ptr, outer.align()).write(Header {
+            magic: ALIVE,
+            size: layout.size(),
+            align: layout.align(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        });
+        ptr.sub(REDZONE_SIZE)
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        ptr.write_bytes(ALLOC_POISON, layout.size());
+        ptr.add(layout.size())
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(corruption) = self.check(ptr, layout) {
+            let header = header(ptr, layout.align().max(MIN_ALIGN)).read();
+            report(corruption, ptr, layout, header);
+        }
+        let outer = outer_layout(layout).unwrap();
+        (*header(ptr, outer.align())).magic = FREED;
+        ptr.write_bytes(FREE_POISON, layout.size());
+        self.inner
+            .dealloc(ptr.sub(data_offset(outer.align())), outer);
+    }
+}
+
+#[cfg(test)]
+struct Heap;
+
+#[cfg(test)]
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::alloc::dealloc(ptr, layout)
+    }
+}
+
+#[test_case]
+fn test_poisons_and_frees() {
+    let allocator = DebugAllocator::new(Heap);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        assert_eq!(ptr as usize % MIN_ALIGN, 0);
+        assert_eq!(*ptr.add(39), ALLOC_POISON);
+        assert_eq!(allocator.check(ptr, layout), Ok(()));
+        allocator.dealloc(ptr, layout);
+        assert_eq!(*ptr.add(39), FREE_POISON);
+        assert_eq!(allocator.check(ptr, layout), Err(Corruption::DoubleFree));
+    }
+}
+
+#[test_case]
+fn test_detects_corruption() {
+    let allocator = DebugAllocator::new(Heap);
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        let wrong = Layout::from_size_align(32, 8).unwrap();
+        assert_eq!(allocator.check(ptr, wrong), Err(Corruption::LayoutMismatch));
+        *ptr.add(24) = 0;
+        assert_eq!(allocator.check(ptr, layout), Err(Corruption::Overflow));
+        *ptr.add(24) = REDZONE_BYTE;
+        *ptr.sub(1) = 0;
+        assert_eq!(allocator.check(ptr, layout), Err(Corruption::Underflow));
+        *ptr.sub(1) = REDZONE_BYTE;
+        allocator.dealloc(ptr, layout);
+    }
+}
@@ -4,18 +4,47 @@ struct ListNode {
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// Number of block sizes.
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len();
+
+/// Block counts of one block size.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SizeClass {
+    pub block_size: usize,
+    pub in_use: usize,
+    /// Blocks in the free list.
+    pub free: usize,
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    stats: AllocStats,
+    size_classes: [SizeClass; BLOCK_SIZES.len()],
+    fallback_allocations: u64,
 }
 
 impl FixedSizeBlockAllocator {
     /// Creates an empty FixedSizeBlockAllocator.
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
+        const CLASS: SizeClass = SizeClass {
+            block_size: 0,
+            in_use: 0,
+            free: 0,
+        };
+        let mut size_classes = [CLASS; BLOCK_SIZES.len()];
+        let mut i = 0;
+        while i < BLOCK_SIZES.len() {
+            size_classes[i].block_size = BLOCK_SIZES[i];
+            i += 1;
+        }
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            stats: AllocStats::new(),
+            size_classes,
+            fallback_allocations: 0,
         }
     }
 
@@ -27,6 +56,37 @@ impl FixedSizeBlockAllocator {
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
     }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    const NAME: &'static str = "fixed-size block";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
+    fn alloc_stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    fn used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    fn block_stats(&self) -> Option<BlockStats> {
+        Some(BlockStats {
+            size_classes: self.size_classes,
+            fallback_allocations: self.fallback_allocations,
+        })
+    }
 }
 
 use alloc::alloc::Layout;
@@ -47,18 +107,19 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-use super::Locked;
+use super::{AllocStats, BlockStats, HeapAllocator, Locked};
 use alloc::alloc::GlobalAlloc;
 use core::{mem, ptr::NonNull};
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
+                let ptr = match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
+                        allocator.size_classes[index].free -= 1;
                         node as *mut ListNode as *mut u8
                     }
                     None => {
@@ -69,16 +130,28 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
                         allocator.fallback_alloc(layout)
                     }
+                };
+                if !ptr.is_null() {
+                    allocator.size_classes[index].in_use += 1;
                 }
+                ptr
             }
-            None => allocator.fallback_alloc(layout),
-        }
+            None => {
+                allocator.fallback_allocations += 1;
+                allocator.fallback_alloc(layout)
+            }
+        };
+        allocator.stats.record_alloc(ptr, layout.size());
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.stats.record_dealloc(layout.size());
         match list_index(&layout) {
             Some(index) => {
+                allocator.size_classes[index].in_use -= 1;
+                allocator.size_classes[index].free += 1;
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
                 };
@@ -1,4 +1,4 @@
-use super::align_up;
+use super::{align_up, AllocStats, HeapAllocator};
 use core::mem;
 
 struct ListNode {
@@ -20,15 +20,60 @@ impl ListNode {
     }
 }
 
+/// How `LinkedListAllocator` picks the free region for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// The region with the lowest address that fits.
+    FirstFit,
+    /// The smallest region that fits, which keeps large regions intact at
+    /// the cost of walking the whole list.
+    BestFit,
+}
+
+/// Allocator keeping the free regions in a list sorted by address, so that
+/// adjacent regions are merged when memory is freed.
 pub struct LinkedListAllocator {
     head: ListNode,
+    strategy: FitStrategy,
+    heap_start: usize,
+    heap_end: usize,
+    stats: AllocStats,
+}
+
+/// Free list figures, as returned by `LinkedListAllocator::free_regions`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FreeRegions {
+    pub count: usize,
+    pub free: usize,
+    pub largest: usize,
+}
+
+impl FreeRegions {
+    /// Share of free memory, in percent, that can't be used for an
+    /// allocation of the largest free region's size.
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            0
+        } else {
+            100 - self.largest * 100 / self.free
+        }
+    }
 }
 
 impl LinkedListAllocator {
-    /// Creates an empty LinkedListAllocator.
+    /// Creates an empty first-fit LinkedListAllocator.
     pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    /// Creates an empty LinkedListAllocator using the given strategy.
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
         Self {
             head: ListNode::new(0),
+            strategy,
+            heap_start: 0,
+            heap_end: 0,
+            stats: AllocStats::new(),
         }
     }
 
@@ -38,44 +83,119 @@ impl LinkedListAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
-    /// Adds the given memory region to the front of the list.
-    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+    pub fn strategy(&self) -> FitStrategy {
+        self.strategy
+    }
+
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    pub fn free_regions(&self) -> FreeRegions {
+        let mut regions = FreeRegions::default();
+        for region in self.regions() {
+            regions.count += 1;
+            regions.free += region.size;
+            regions.largest = regions.largest.max(region.size);
+        }
+        regions
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.head.next.as_deref(), |region| region.next.as_deref())
+    }
+
+    /// Adds the given memory region to the list, merging it with the free
+    /// regions directly before and after it.
+    unsafe fn add_free_region(&mut self, addr: usize, mut size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
+        // find the last region before the freed one (or the head)
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        assert!(
+            current.size == 0 || current.end_addr() <= addr,
+            "freed region {:#x} overlaps a free region",
+            addr
+        );
+
+        // merge with the following region
+        if let Some(next) = current.next.as_ref() {
+            assert!(
+                addr + size <= next.start_addr(),
+                "freed region {:#x} overlaps a free region",
+                addr
+            );
+            if addr + size == next.start_addr() {
+                let next = current.next.take().unwrap();
+                size += next.size;
+                current.next = next.next.take();
+            }
+        }
+
+        // merge with the preceding region; the head has size 0
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += size;
+            return;
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let node_ptr = addr as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        current.next = Some(&mut *node_ptr)
     }
 
-    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+    /// Removes the free region starting at `addr` from the list.
+    fn take_region(&mut self, addr: usize) -> Option<&'static mut ListNode> {
         // reference to current list node, updated for each iteration
         let mut current = &mut self.head;
-        // look for a large enough memory region in linked list
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
-                // region suitable for allocation -> remove node from list
+            if region.start_addr() == addr {
                 let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
+                let ret = current.next.take();
                 current.next = next;
                 return ret;
-            } else {
-                // region not suitable -> continue with next region
-                current = current.next.as_mut().unwrap();
+            } else if region.start_addr() > addr {
+                return None;
             }
+            current = current.next.as_mut().unwrap();
         }
-
-        // no suitable region found
         None
     }
 
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let fits = |region: &&ListNode| Self::alloc_from_region(region, size, align).is_ok();
+        let addr = match self.strategy {
+            FitStrategy::FirstFit => self.regions().find(fits)?.start_addr(),
+            FitStrategy::BestFit => self
+                .regions()
+                .filter(fits)
+                .min_by_key(|region| region.size)?
+                .start_addr(),
+        };
+        let region = self.take_region(addr)?;
+        let alloc_start = Self::alloc_from_region(region, size, align).ok()?;
+        Some((region, alloc_start))
+    }
+
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
         let alloc_start = align_up(region.start_addr(), align);
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
@@ -96,6 +216,41 @@ impl LinkedListAllocator {
         Ok(alloc_start)
     }
 
+    /// Grows or shrinks the allocation at `addr` from `old_size` to
+    /// `new_size` bytes (both as returned by `size_align`) without moving
+    /// it. Fails if the memory after it isn't free.
+    unsafe fn resize_in_place(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let old_end = addr + old_size;
+        let new_end = addr + new_size;
+        if new_size <= old_size {
+            let excess = old_size - new_size;
+            if excess == 0 {
+                return true;
+            }
+            if excess < mem::size_of::<ListNode>() {
+                // the tail couldn't be freed and would be lost
+                return false;
+            }
+            self.add_free_region(new_end, excess);
+            return true;
+        }
+
+        let needed = new_size - old_size;
+        let next_size = match self.regions().find(|region| region.start_addr() == old_end) {
+            Some(region) => region.size,
+            None => return false,
+        };
+        let excess = match next_size.checked_sub(needed) {
+            Some(excess) if excess == 0 || excess >= mem::size_of::<ListNode>() => excess,
+            _ => return false,
+        };
+        self.take_region(old_end);
+        if excess > 0 {
+            self.add_free_region(new_end, excess);
+        }
+        true
+    }
+
     fn size_align(layout: Layout) -> (usize, usize) {
         let layout = layout
             .align_to(mem::align_of::<ListNode>())
@@ -106,6 +261,28 @@ impl LinkedListAllocator {
     }
 }
 
+impl HeapAllocator for LinkedListAllocator {
+    const NAME: &'static str = "linked list";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        let end = self.heap_end;
+        self.heap_end += by;
+        self.add_free_region(end, by);
+    }
+
+    fn alloc_stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    fn used(&self) -> usize {
+        self.heap_end - self.heap_start - self.free_regions().free
+    }
+}
+
 use super::Locked;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
@@ -116,7 +293,7 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let ptr = if let Some((region, alloc_start)) = allocator.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 {
@@ -125,13 +302,38 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
-        }
+        };
+        allocator.stats.record_alloc(ptr, layout.size());
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         // perform layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.stats.record_dealloc(layout.size());
+        allocator.add_free_region(ptr as usize, size)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_block, _) = LinkedListAllocator::size_align(layout);
+        let (new_block, _) = LinkedListAllocator::size_align(new_layout);
+        {
+            let mut allocator = self.lock();
+            if allocator.resize_in_place(ptr as usize, old_block, new_block) {
+                allocator.stats.record_resize(layout.size(), new_size);
+                return ptr;
+            }
+        }
+
+        // move the allocation, with the lock released
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
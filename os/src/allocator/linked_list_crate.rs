@@ -0,0 +1,60 @@
+use super::{AllocStats, HeapAllocator, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+/// The allocator of the `linked_list_allocator` crate, with statistics.
+pub struct CrateLinkedListAllocator {
+    heap: Heap,
+    stats: AllocStats,
+}
+
+impl CrateLinkedListAllocator {
+    /// Creates an empty CrateLinkedListAllocator.
+    pub const fn new() -> Self {
+        CrateLinkedListAllocator {
+            heap: Heap::empty(),
+            stats: AllocStats::new(),
+        }
+    }
+}
+
+impl HeapAllocator for CrateLinkedListAllocator {
+    const NAME: &'static str = "linked_list_allocator crate";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap.extend(by);
+    }
+
+    fn alloc_stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    fn used(&self) -> usize {
+        self.heap.used()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<CrateLinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = match allocator.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        };
+        allocator.stats.record_alloc(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.stats.record_dealloc(layout.size());
+        allocator
+            .heap
+            .deallocate(NonNull::new(ptr).unwrap(), layout);
+    }
+}
@@ -0,0 +1,358 @@
+//! Slab caches for fixed-size kernel objects.
+//!
+//! A cache hands out objects of a single size from slabs, which are single
+//! physical frames accessed through the physical memory mapping, so caches
+//! don't use the heap at all. Each slab starts with a header followed by as
+//! many objects as fit; free objects are linked through their first word.
+//! Slabs with free objects are kept in a list, and a slab is given back to
+//! the frame allocator as soon as its last object is freed.
+
+use crate::memory::{self, GlobalFrameAllocator};
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr,
+};
+
+const SLAB_SIZE: usize = 4096;
+/// Caches registered after this many aren't listed by `cache_stats`.
+const MAX_CACHES: usize = 32;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Stored at the start of each slab.
+struct SlabHeader {
+    /// Neighbours in the list of slabs with free objects.
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Usage figures of a cache, as returned by `SlabCache::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl CacheStats {
+    /// Free objects in the cache's slabs.
+    pub fn free_objects(&self) -> usize {
+        self.slabs * self.objects_per_slab - self.in_use
+    }
+}
+
+struct Slabs {
+    /// Slabs with at least one free object. Full slabs aren't tracked.
+    partial: *mut SlabHeader,
+    stats: CacheStats,
+}
+
+// the slabs are only reached through the cache's lock
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        let SlabHeader { next, prev, .. } = *slab;
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+}
+
+/// A named cache of objects of one size and alignment.
+pub struct SlabCache {
+    object_size: usize,
+    /// Offset of the first object in a slab.
+    first_object: usize,
+    objects_per_slab: usize,
+    registered: AtomicBool,
+    slabs: Mutex<Slabs>,
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+impl SlabCache {
+    /// Creates a cache for objects of `size` bytes aligned to `align`.
+    ///
+    /// Panics if not even one object fits into a slab.
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two());
+        let align = if align < mem::align_of::<FreeObject>() {
+            mem::align_of::<FreeObject>()
+        } else {
+            align
+        };
+        let size = if size < mem::size_of::<FreeObject>() {
+            mem::size_of::<FreeObject>()
+        } else {
+            size
+        };
+        let object_size = align_up(size, align);
+        let first_object = align_up(mem::size_of::<SlabHeader>(), align);
+        assert!(
+            first_object + object_size <= SLAB_SIZE,
+            "object too large for a slab"
+        );
+        let objects_per_slab = (SLAB_SIZE - first_object) / object_size;
+        SlabCache {
+            object_size,
+            first_object,
+            objects_per_slab,
+            registered: AtomicBool::new(false),
+            slabs: Mutex::new(Slabs {
+                partial: ptr::null_mut(),
+                stats: CacheStats {
+                    name,
+                    object_size,
+                    objects_per_slab,
+                    slabs: 0,
+                    in_use: 0,
+                    allocations: 0,
+                    frees: 0,
+                },
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.slabs.lock().stats
+    }
+
+    /// Allocates an object, or returns `None` if there are no frames left.
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        self.register();
+        let mut slabs = self.slabs.lock();
+        if slabs.partial.is_null() {
+            let slab = self.new_slab()?;
+            unsafe { slabs.push(slab) };
+            slabs.stats.slabs += 1;
+        }
+
+        let slab = slabs.partial;
+        let object = unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                slabs.remove(slab);
+            }
+            object
+        };
+        slabs.stats.in_use += 1;
+        slabs.stats.allocations += 1;
+        NonNull::new(object as *mut u8)
+    }
+
+    /// Returns an object to the cache, freeing its slab if it was the last
+    /// one in use.
+    ///
+    /// Unsafe because `object` must have been allocated from this cache and
+    /// must not be used anymore.
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let addr = object.as_ptr() as usize;
+        let slab = (addr & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let offset = addr - slab as usize;
+        assert!(
+            offset >= self.first_object && (offset - self.first_object) % self.object_size == 0,
+            "{:#x} is not an object of this cache",
+            addr
+        );
+
+        let mut slabs = self.slabs.lock();
+        let was_full = (*slab).free.is_null();
+        let object = object.as_ptr() as *mut FreeObject;
+        object.write(FreeObject { next: (*slab).free });
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        slabs.stats.in_use -= 1;
+        slabs.stats.frees += 1;
+
+        if (*slab).in_use == 0 {
+            if !was_full {
+                slabs.remove(slab);
+            }
+            slabs.stats.slabs -= 1;
+            let phys = slab as u64 - memory::physical_memory_offset().as_u64();
+            GlobalFrameAllocator
+                .deallocate_frame(PhysFrame::containing_address(PhysAddr::new(phys)));
+        } else if was_full {
+            slabs.push(slab);
+        }
+    }
+
+    /// Takes a frame from the frame allocator and threads all objects in it
+    /// onto its free list.
+    fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let frame = GlobalFrameAllocator.allocate_frame()?;
+        let slab: *mut SlabHeader = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        let object = |i: usize| {
+            (slab as usize + self.first_object + i * self.object_size) as *mut FreeObject
+        };
+        unsafe {
+            for i in 0..self.objects_per_slab {
+                let next = if i + 1 < self.objects_per_slab {
+                    object(i + 1)
+                } else {
+                    ptr::null_mut()
+                };
+                object(i).write(FreeObject { next });
+            }
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free: object(0),
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let mut caches = CACHES.lock();
+        if let Some(slot) = caches.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(self);
+        }
+    }
+}
+
+/// Caches that have allocated at least once.
+static CACHES: Mutex<[Option<&'static SlabCache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Statistics of all caches in use, in the order they were first used.
+pub fn cache_stats() -> Vec<CacheStats> {
+    let caches = *CACHES.lock();
+    caches.iter().flatten().map(|cache| cache.stats()).collect()
+}
+
+/// A slab cache for values of type `T`.
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        ObjectCache {
+            cache: SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves `value` into an object of the cache.
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let ptr = self.cache.alloc()?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+/// An owned value stored in an `ObjectCache`, freed when dropped.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.free(self.ptr.cast());
+        }
+    }
+}
+
+#[test_case]
+fn test_empty_slabs_are_freed() {
+    static CACHE: SlabCache = SlabCache::new("test", 100, 8);
+    let frames_before = memory::frame_stats().free_frames;
+    let count = CACHE.stats().objects_per_slab + 1;
+    let objects = (0..count)
+        .map(|_| CACHE.alloc().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(CACHE.stats().slabs, 2);
+    assert_eq!(CACHE.stats().in_use, count);
+    for object in objects {
+        unsafe { CACHE.free(object) };
+    }
+    assert_eq!(CACHE.stats().slabs, 0);
+    assert_eq!(memory::frame_stats().free_frames, frames_before);
+}
+
+#[test_case]
+fn test_slab_box() {
+    static CACHE: ObjectCache<[u64; 3]> = ObjectCache::new("test box");
+    let a = CACHE.alloc([1, 2, 3]).unwrap();
+    let mut b = CACHE.alloc([4, 5, 6]).unwrap();
+    b[0] = 7;
+    assert_eq!(*a, [1, 2, 3]);
+    assert_eq!(*b, [7, 5, 6]);
+    assert_eq!(CACHE.stats().in_use, 2);
+    drop(a);
+    drop(b);
+    assert_eq!(CACHE.stats().in_use, 0);
+    assert!(cache_stats().iter().any(|stats| stats.name == "test box"));
+}
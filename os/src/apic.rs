@@ -0,0 +1,290 @@
+//! Local APIC and I/O APIC, replacing the legacy 8259 PICs.
+//!
+//! `init` finds both through the ACPI MADT and masks the PICs. ISA
+//! interrupts are routed to their vectors through I/O APIC redirection
+//! entries, which `irq` unmasks as handlers are registered. The timer
+//! interrupt comes from the local APIC timer instead of the PIT; it's
+//! calibrated against PIT channel 2 to fire at `time::TIMER_FREQUENCY`.
+//!
+//! Without a MADT, `init` leaves the PICs in charge.
+
+use crate::{
+    acpi::{self, AcpiError, Madt},
+    interrupts::{InterruptIndex, PICS},
+    memory, time,
+};
+use alloc::vec::Vec;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::Msr,
+    structures::paging::{mapper::MapToError, PageSize},
+    VirtAddr,
+};
+
+/// Vector of the local APIC's spurious interrupts. Its low four bits must
+/// be set on older processors.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// local APIC registers
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+const CALIBRATION_MS: u32 = 10;
+
+// I/O APIC registers, accessed through a select and a window register
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Virtual address of the local APIC's registers, 0 until it's enabled.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer counts per timer tick.
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+/// Set up by `init`, locked with interrupts disabled.
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    Acpi(AcpiError),
+    NoIoApic,
+    MapFailed,
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(err: AcpiError) -> Self {
+        ApicError::Acpi(err)
+    }
+}
+
+impl<S: PageSize> From<MapToError<S>> for ApicError {
+    fn from(_: MapToError<S>) -> Self {
+        ApicError::MapFailed
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn register(&self, offset: usize) -> *mut u32 {
+        (self.base + offset as u64).as_mut_ptr()
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.register(IOREGSEL), register);
+            ptr::read_volatile(self.register(IOWIN))
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.register(IOREGSEL), register);
+            ptr::write_volatile(self.register(IOWIN), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION + 2 * (gsi - self.gsi_base);
+        // masked while the halves don't match
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+struct Routing {
+    madt: Madt,
+    io_apics: Vec<IoApic>,
+    /// Local APIC id of the processor interrupts are delivered to.
+    destination: u8,
+}
+
+impl Routing {
+    /// Points the redirection entry of ISA interrupt `irq` at `vector`,
+    /// masked or not.
+    fn route_isa_irq(&self, irq: u8, vector: u8, masked: bool) {
+        let isa = self.madt.isa_interrupt(irq);
+        let io_apic = match self
+            .io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(isa.gsi))
+        {
+            Some(io_apic) => io_apic,
+            None => return,
+        };
+        let mut entry = u64::from(vector) | u64::from(self.destination) << 56;
+        if isa.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if isa.level_triggered {
+            entry |= REDIRECTION_LEVEL;
+        }
+        if masked {
+            entry |= REDIRECTION_MASKED;
+        }
+        io_apic.set_redirection(isa.gsi, entry);
+    }
+}
+
+fn local_apic(register: usize) -> *mut u32 {
+    (LOCAL_APIC.load(Ordering::Relaxed) as usize + register) as *mut u32
+}
+
+fn read(register: usize) -> u32 {
+    unsafe { ptr::read_volatile(local_apic(register)) }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { ptr::write_volatile(local_apic(register), value) }
+}
+
+/// Returns `true` once `init` switched to the APICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+/// Switches interrupt delivery from the PICs to the APICs.
+///
+/// Must be called once during boot, after the memory is initialized. On
+/// failure, the PICs stay in charge.
+pub fn init() -> Result<(), ApicError> {
+    let madt = acpi::madt()?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+    let local_apic = memory::map_mmio(madt.local_apic, 4096)?;
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|info| {
+            let io_apic = IoApic {
+                base: memory::map_mmio(info.address, 0x20)?,
+                gsi_base: info.gsi_base,
+                entries: 0,
+            };
+            let entries = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1;
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + entries {
+                io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+            }
+            Ok(IoApic { entries, ..io_apic })
+        })
+        .collect::<Result<Vec<_>, ApicError>>()?;
+
+    interrupts::without_interrupts(|| {
+        if madt.has_legacy_pics {
+            unsafe { PICS.lock().disable() };
+        }
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_GLOBAL_ENABLE);
+        }
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::Relaxed);
+        write(TASK_PRIORITY, 0);
+        write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+        // the firmware may have left the PICs connected, spurious interrupts
+        // included
+        write(LVT_LINT0, LVT_MASKED);
+        start_timer(calibrate_timer());
+
+        let routing = Routing {
+            madt,
+            io_apics,
+            destination: (read(ID) >> 24) as u8,
+        };
+        *ROUTING.lock() = Some(routing);
+    });
+    Ok(())
+}
+
+/// Counts the local APIC timer runs down in a timer tick, measured on the
+/// PIT.
+fn calibrate_timer() -> u32 {
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    time::pit_delay(CALIBRATION_MS);
+    let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+    let per_second = u64::from(elapsed) * 1000 / u64::from(CALIBRATION_MS);
+    (per_second / u64::from(time::TIMER_FREQUENCY)).max(1) as u32
+}
+
+fn start_timer(count: u32) {
+    TIMER_COUNT.store(count, Ordering::Relaxed);
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(
+        LVT_TIMER,
+        TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()),
+    );
+    write(TIMER_INITIAL_COUNT, count);
+}
+
+/// Local APIC timer counts per timer tick, 0 while the PIT drives the timer.
+pub fn timer_count() -> u32 {
+    TIMER_COUNT.load(Ordering::Relaxed)
+}
+
+/// Signals the end of an interrupt to the local APIC.
+pub(crate) fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Masks or unmasks ISA interrupt `irq` in the I/O APIC, delivering it to
+/// `vector`. Does nothing before `init`.
+pub fn route_isa_irq(irq: u8, vector: u8, masked: bool) {
+    interrupts::without_interrupts(|| {
+        if let Some(routing) = ROUTING.lock().as_ref() {
+            routing.route_isa_irq(irq, vector, masked);
+        }
+    });
+}
+
+#[test_case]
+fn test_timer_is_calibrated() {
+    assert!(is_enabled());
+    assert!(timer_count() > 0);
+    // the timer keeps ticking at about the PIT's rate
+    let start = time::ticks();
+    time::pit_delay(50);
+    let ticks = time::ticks() - start;
+    let expected = time::ms_to_ticks(50);
+    assert!(
+        ticks >= expected / 2 && ticks <= expected * 2,
+        "{} ticks in 50 ms",
+        ticks
+    );
+}
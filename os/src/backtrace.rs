@@ -0,0 +1,192 @@
+//! Stack backtraces for panics and exceptions.
+//!
+//! The kernel is built with frame pointers (see `.cargo/config.toml`), so
+//! `rbp` points at the caller's saved `rbp`, followed by the return address.
+//!
+//! Return addresses are resolved with a symbol table in the `.ksyms`
+//! section. The section is reserved empty at link time and filled in by
+//! `tools/runner.sh` before booting: one `ADDRESS NAME` line per function,
+//! the address in hex, sorted by address and padded with NULs. Kernels
+//! booted without it get backtraces of bare addresses.
+
+use crate::memory;
+use core::{
+    arch::{asm, global_asm},
+    fmt::{self, Write},
+    slice, str,
+};
+use x86_64::VirtAddr;
+
+/// Frames beyond this are left out, so a runaway recursion doesn't scroll
+/// the interesting part off the crash screen.
+const MAX_FRAMES: usize = 32;
+
+// keep the size in sync with `KSYMS_SIZE` in `tools/runner.sh`
+global_asm!(
+    r#"
+.pushsection .ksyms, "a", @progbits
+.global ksyms_start
+ksyms_start:
+    .skip 0x100000
+.global ksyms_end
+ksyms_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static ksyms_start: u8;
+    static ksyms_end: u8;
+}
+
+fn symbol_table() -> &'static str {
+    let table = unsafe {
+        let start = &ksyms_start as *const u8;
+        let len = &ksyms_end as *const u8 as usize - start as usize;
+        slice::from_raw_parts(start, len)
+    };
+    let len = table
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(table.len());
+    str::from_utf8(&table[..len]).unwrap_or("")
+}
+
+/// The function containing an address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Looks up the function containing `addr`.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    let mut found = None;
+    for line in symbol_table().lines() {
+        let (start, name) = match line.split_once(' ') {
+            Some(line) => line,
+            None => continue,
+        };
+        let start = match u64::from_str_radix(start, 16) {
+            Ok(start) => start,
+            Err(_) => continue,
+        };
+        if start > addr {
+            break;
+        }
+        found = Some(Symbol {
+            name,
+            offset: addr - start,
+        });
+    }
+    found
+}
+
+/// Return addresses found by following the frame pointer chain.
+pub struct Frames {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Frames {
+    /// Walks the frames of the function whose frame pointer is `rbp` and its
+    /// callers. Stops at the first frame pointer that isn't mapped, lies in
+    /// user space or isn't above the previous one.
+    pub fn new(rbp: u64) -> Self {
+        Frames { rbp, depth: 0 }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        let user_space = memory::USER_SPACE_START..memory::USER_SPACE_END;
+        let mapped = |addr| VirtAddr::try_new(addr).map_or(false, memory::is_mapped);
+        let valid = rbp != 0
+            && !user_space.contains(&rbp)
+            && rbp % 8 == 0
+            && self.depth < MAX_FRAMES
+            && mapped(rbp)
+            && mapped(rbp + 8);
+        if !valid {
+            return None;
+        }
+        let (caller_rbp, return_addr) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_addr == 0 {
+            return None;
+        }
+        // the stack grows down, so callers' frames are at higher addresses
+        self.rbp = if caller_rbp > rbp { caller_rbp } else { 0 };
+        self.depth += 1;
+        Some(return_addr)
+    }
+}
+
+fn write_frame(out: &mut dyn Write, index: usize, addr: u64, is_return: bool) -> fmt::Result {
+    write!(out, "{:3}: {:#018x}", index, addr)?;
+    // a return address may be just past the end of a function ending in a
+    // call that doesn't return, so look up the call instruction instead
+    let lookup = if is_return { addr - 1 } else { addr };
+    match resolve(lookup) {
+        Some(symbol) => writeln!(out, " {}+{:#x}", symbol.name, symbol.offset + addr - lookup),
+        None => writeln!(out),
+    }
+}
+
+/// Writes the backtrace of an interrupted context: `rip`, followed by the
+/// callers found from its frame pointer `rbp`.
+pub fn write_from(out: &mut dyn Write, rip: u64, rbp: u64) -> fmt::Result {
+    writeln!(out, "backtrace:")?;
+    write_frame(out, 0, rip, false)?;
+    for (index, addr) in Frames::new(rbp).enumerate() {
+        write_frame(out, index + 1, addr, true)?;
+    }
+    Ok(())
+}
+
+/// Writes the backtrace of the caller.
+#[inline(never)]
+pub fn write_current(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "backtrace:")?;
+    for (index, addr) in Frames::new(current_rbp()).enumerate() {
+        write_frame(out, index, addr, true)?;
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+#[test_case]
+fn test_resolves_functions() {
+    let addr = write_current as usize as u64;
+    let symbol = resolve(addr + 4).expect("no symbol table, run through tools/runner.sh");
+    assert!(symbol.name.ends_with("backtrace::write_current"));
+    assert_eq!(symbol.offset, 4);
+}
+
+#[test_case]
+fn test_walks_frames() {
+    #[inline(never)]
+    fn first_frame() -> u64 {
+        Frames::new(current_rbp()).next().unwrap()
+    }
+
+    let symbol = resolve(first_frame() - 1).unwrap();
+    assert!(symbol.name.ends_with("backtrace::test_walks_frames"));
+}
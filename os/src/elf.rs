@@ -0,0 +1,186 @@
+//! Parser for the ELF64 executables run as user processes.
+//!
+//! Only what the loader in `process` needs is looked at: the file header and
+//! the `PT_LOAD` program headers of little-endian x86_64 executables.
+
+use core::fmt;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    /// Not a 64-bit little-endian x86_64 file.
+    Unsupported,
+    /// Neither `ET_EXEC` nor `ET_DYN`.
+    NotExecutable,
+    /// Needs a dynamic linker.
+    Interpreter,
+    BadProgramHeader,
+    /// A segment's file data lies outside the file or exceeds its memory size.
+    BadSegment,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ElfError::TooShort => "file too short",
+            ElfError::BadMagic => "not an ELF file",
+            ElfError::Unsupported => "not a 64-bit x86_64 ELF file",
+            ElfError::NotExecutable => "not an executable",
+            ElfError::Interpreter => "dynamically linked",
+            ElfError::BadProgramHeader => "malformed program header",
+            ElfError::BadSegment => "malformed segment",
+        })
+    }
+}
+
+/// A loadable segment.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    pub vaddr: u64,
+    /// Size in memory; the bytes after `data` are zero-filled.
+    pub mem_size: u64,
+    pub data: &'a [u8],
+    pub writable: bool,
+    pub executable: bool,
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    kind: u16,
+    entry: u64,
+    program_headers: &'a [u8],
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> ElfFile<'a> {
+    /// Validates the file header and locates the program headers.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64
+            || data[5] != DATA_LITTLE_ENDIAN
+            || u16_at(data, 18) != MACHINE_X86_64
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let kind = u16_at(data, 16);
+        if kind != TYPE_EXEC && kind != TYPE_DYN {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let phoff = u64_at(data, 32) as usize;
+        let phentsize = u16_at(data, 54) as usize;
+        let phnum = u16_at(data, 56) as usize;
+        if phentsize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let program_headers = phoff
+            .checked_add(phnum * PROGRAM_HEADER_SIZE)
+            .and_then(|end| data.get(phoff..end))
+            .ok_or(ElfError::BadProgramHeader)?;
+
+        let file = ElfFile {
+            data,
+            kind,
+            entry: u64_at(data, 24),
+            program_headers,
+        };
+        if file.program_types().any(|kind| kind == PT_INTERP) {
+            return Err(ElfError::Interpreter);
+        }
+        Ok(file)
+    }
+
+    /// Entry point, relative to the load bias for position independent files.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// `true` for `ET_DYN` files, which may be loaded at any address.
+    pub fn is_position_independent(&self) -> bool {
+        self.kind == TYPE_DYN
+    }
+
+    fn program_types(&self) -> impl Iterator<Item = u32> + 'a {
+        self.program_headers
+            .chunks_exact(PROGRAM_HEADER_SIZE)
+            .map(|header| u32_at(header, 0))
+    }
+
+    /// The `PT_LOAD` segments, in file order.
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment<'a>, ElfError>> + 'a {
+        let data = self.data;
+        self.program_headers
+            .chunks_exact(PROGRAM_HEADER_SIZE)
+            .filter(|header| u32_at(header, 0) == PT_LOAD)
+            .map(move |header| {
+                let flags = u32_at(header, 4);
+                let offset = u64_at(header, 8) as usize;
+                let vaddr = u64_at(header, 16);
+                let file_size = u64_at(header, 32) as usize;
+                let mem_size = u64_at(header, 40);
+                if file_size as u64 > mem_size || vaddr.checked_add(mem_size).is_none() {
+                    return Err(ElfError::BadSegment);
+                }
+                let data = offset
+                    .checked_add(file_size)
+                    .and_then(|end| data.get(offset..end))
+                    .ok_or(ElfError::BadSegment)?;
+                Ok(Segment {
+                    vaddr,
+                    mem_size,
+                    data,
+                    writable: flags & PF_W != 0,
+                    executable: flags & PF_X != 0,
+                })
+            })
+    }
+}
+
+#[test_case]
+fn test_rejects_garbage() {
+    assert_eq!(ElfFile::parse(&[0; 16]).err(), Some(ElfError::TooShort));
+    assert_eq!(ElfFile::parse(&[0; 64]).err(), Some(ElfError::BadMagic));
+}
+
+#[test_case]
+fn test_parses_initrd_programs() {
+    for file in crate::initrd::files() {
+        let elf = ElfFile::parse(file.data).expect("initrd program is not a valid ELF file");
+        assert!(elf.segments().all(|segment| segment.is_ok()));
+        assert!(elf.segments().count() > 0);
+    }
+}
@@ -0,0 +1,381 @@
+//! Handlers for the CPU exceptions.
+//!
+//! Every exception vector has a small entry stub, `exception_entry_N`, that
+//! pushes the vector number, plus a zero for exceptions without an error
+//! code, so all of them reach `exception_common` with the same stack layout.
+//! That saves all general purpose registers and calls `exception_inner` with
+//! a pointer to the resulting `ExceptionContext`.
+//!
+//! Debug and breakpoint exceptions are reported and resumed, and so are page
+//! faults in lazy areas (see `memory::vma`) once the page is mapped. Any
+//! other exception raised in user mode kills the process. In the kernel it's
+//! fatal: its context is written to a red crash screen and to serial, then
+//! the kernel panics.
+
+use crate::{
+    backtrace, gdt, memory, print, println, process, serial,
+    task::stats,
+    thread,
+    vga_buffer::{Color, CrashScreen},
+};
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+};
+use x86_64::{
+    registers::control::{Cr2, Cr3},
+    structures::idt::{
+        Entry, HandlerFuncWithErrCode, InterruptDescriptorTable, PageFaultErrorCode,
+    },
+    VirtAddr,
+};
+
+pub const DEBUG: u64 = 1;
+pub const BREAKPOINT: u64 = 3;
+pub const DOUBLE_FAULT: u64 = 8;
+pub const PAGE_FAULT: u64 = 14;
+
+/// Mnemonic and name of each exception vector.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("#DE", "DIVIDE ERROR"),
+    ("#DB", "DEBUG"),
+    ("NMI", "NON-MASKABLE INTERRUPT"),
+    ("#BP", "BREAKPOINT"),
+    ("#OF", "OVERFLOW"),
+    ("#BR", "BOUND RANGE EXCEEDED"),
+    ("#UD", "INVALID OPCODE"),
+    ("#NM", "DEVICE NOT AVAILABLE"),
+    ("#DF", "DOUBLE FAULT"),
+    ("---", "COPROCESSOR SEGMENT OVERRUN"),
+    ("#TS", "INVALID TSS"),
+    ("#NP", "SEGMENT NOT PRESENT"),
+    ("#SS", "STACK-SEGMENT FAULT"),
+    ("#GP", "GENERAL PROTECTION FAULT"),
+    ("#PF", "PAGE FAULT"),
+    ("---", "RESERVED"),
+    ("#MF", "X87 FLOATING-POINT EXCEPTION"),
+    ("#AC", "ALIGNMENT CHECK"),
+    ("#MC", "MACHINE CHECK"),
+    ("#XM", "SIMD FLOATING-POINT EXCEPTION"),
+    ("#VE", "VIRTUALIZATION EXCEPTION"),
+    ("#CP", "CONTROL PROTECTION EXCEPTION"),
+    ("---", "RESERVED"),
+    ("---", "RESERVED"),
+    ("---", "RESERVED"),
+    ("---", "RESERVED"),
+    ("---", "RESERVED"),
+    ("---", "RESERVED"),
+    ("#HV", "HYPERVISOR INJECTION EXCEPTION"),
+    ("#VC", "VMM COMMUNICATION EXCEPTION"),
+    ("#SX", "SECURITY EXCEPTION"),
+    ("---", "RESERVED"),
+];
+
+/// Registers saved by `exception_common`, the vector and error code pushed
+/// by the entry stub or the CPU, and the frame the CPU pushed.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions without an error code.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionContext {
+    pub fn name(&self) -> &'static str {
+        EXCEPTIONS[self.vector as usize].1
+    }
+
+    /// Returns `true` if the exception was raised in user mode.
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// Points the IDT entries of all exceptions at their entry stubs.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    let entry = |vector: usize| VirtAddr::new(unsafe { exception_entries[vector] });
+    unsafe {
+        idt.divide_error.set_handler_addr(entry(0));
+        idt.debug.set_handler_addr(entry(1));
+        idt.non_maskable_interrupt.set_handler_addr(entry(2));
+        idt.breakpoint.set_handler_addr(entry(3));
+        idt.overflow.set_handler_addr(entry(4));
+        idt.bound_range_exceeded.set_handler_addr(entry(5));
+        idt.invalid_opcode.set_handler_addr(entry(6));
+        idt.device_not_available.set_handler_addr(entry(7));
+        idt.double_fault
+            .set_handler_addr(entry(8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(entry(10));
+        idt.segment_not_present.set_handler_addr(entry(11));
+        idt.stack_segment_fault.set_handler_addr(entry(12));
+        idt.general_protection_fault.set_handler_addr(entry(13));
+        idt.page_fault.set_handler_addr(entry(14));
+        idt.x87_floating_point.set_handler_addr(entry(16));
+        idt.alignment_check.set_handler_addr(entry(17));
+        idt.machine_check.set_handler_addr(entry(18));
+        idt.simd_floating_point.set_handler_addr(entry(19));
+        idt.virtualization.set_handler_addr(entry(20));
+        idt.vmm_communication_exception.set_handler_addr(entry(29));
+        idt.security_exception.set_handler_addr(entry(30));
+
+        // x86_64 0.14 keeps the control protection exception (#CP) among its
+        // private reserved entries, so it's set through the table's C layout
+        let entries = idt as *mut InterruptDescriptorTable as *mut Entry<HandlerFuncWithErrCode>;
+        (*entries.add(21)).set_handler_addr(entry(21));
+    }
+}
+
+/// Called by `exception_common` with the stack pointer to the
+/// `ExceptionContext`; returns the stack pointer to resume from.
+#[no_mangle]
+extern "C" fn exception_inner(rsp: u64) -> u64 {
+    let context = unsafe { &mut *(rsp as *mut ExceptionContext) };
+    match context.vector {
+        DEBUG | BREAKPOINT => {
+            println!("EXCEPTION: {} at {:#x}", context.name(), context.rip);
+        }
+        PAGE_FAULT if memory::vma::handle_fault(Cr2::read(), page_fault_error(context)) => {}
+        _ if context.from_user() => kill(context),
+        _ => crash(context),
+    }
+    rsp
+}
+
+fn page_fault_error(context: &ExceptionContext) -> PageFaultErrorCode {
+    PageFaultErrorCode::from_bits_truncate(context.error_code)
+}
+
+/// Kills the process that raised an exception in user mode, leaving the
+/// rest of the system running.
+fn kill(context: &mut ExceptionContext) {
+    let process = match thread::current_process() {
+        Some(process) => process,
+        None => crash(context),
+    };
+    print!(
+        FG: Color::LightRed,
+        "\n{} (pid {}) killed: {} at {:#x}",
+        process.name(),
+        process.pid(),
+        context.name(),
+        context.rip
+    );
+    if context.vector == PAGE_FAULT {
+        print!(FG: Color::LightRed, ", accessing {:#x}", Cr2::read().as_u64());
+    }
+    println!();
+    process::kill(context);
+}
+
+/// Writes the crash screen and serial at once.
+pub struct CrashLog {
+    screen: CrashScreen,
+}
+
+impl CrashLog {
+    /// Shows the crash screen, or keeps writing to it if it's already shown.
+    pub fn new() -> Self {
+        CrashLog {
+            screen: CrashScreen::new(),
+        }
+    }
+}
+
+impl Default for CrashLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for CrashLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.screen.write_str(s)?;
+        serial::force_lock().write_str(s)
+    }
+}
+
+/// Reports a fatal exception and panics.
+fn crash(context: &ExceptionContext) -> ! {
+    let cr2 = Cr2::read();
+    let stack_overflow =
+        matches!(context.vector, DOUBLE_FAULT | PAGE_FAULT) && memory::stack::is_guard_page(cr2);
+    let mut log = CrashLog::new();
+    let _ = write_report(&mut log, context, cr2);
+    // user code's frames can't be resolved, and its frame pointer can't be trusted
+    if !context.from_user() {
+        let _ = backtrace::write_from(&mut log, context.rip, context.rbp);
+    }
+    if stack_overflow {
+        panic!("stack overflow in {}", Running);
+    }
+    panic!("EXCEPTION: {} in {}", context.name(), Running);
+}
+
+fn write_report(out: &mut CrashLog, c: &ExceptionContext, cr2: VirtAddr) -> fmt::Result {
+    let (mnemonic, name) = EXCEPTIONS[c.vector as usize];
+    writeln!(
+        out,
+        "EXCEPTION: {} ({}, vector {})",
+        name, mnemonic, c.vector
+    )?;
+    writeln!(out, "in {}", Running)?;
+    write!(out, "error code: {:#x}", c.error_code)?;
+    if c.vector == PAGE_FAULT {
+        write!(out, " {:?}", page_fault_error(c))?;
+    }
+    writeln!(out)?;
+    writeln!(
+        out,
+        "rip={:016x} cs={:04x} rflags={:016x}",
+        c.rip, c.cs, c.rflags
+    )?;
+    writeln!(out, "rsp={:016x} ss={:04x}", c.rsp, c.ss)?;
+    let registers = [
+        ("rax", c.rax),
+        ("rbx", c.rbx),
+        ("rcx", c.rcx),
+        ("rdx", c.rdx),
+        ("rsi", c.rsi),
+        ("rdi", c.rdi),
+        ("rbp", c.rbp),
+        ("r8 ", c.r8),
+        ("r9 ", c.r9),
+        ("r10", c.r10),
+        ("r11", c.r11),
+        ("r12", c.r12),
+        ("r13", c.r13),
+        ("r14", c.r14),
+        ("r15", c.r15),
+    ];
+    for row in registers.chunks(3) {
+        for (name, value) in row {
+            write!(out, "{}={:016x} ", name, value)?;
+        }
+        writeln!(out)?;
+    }
+    let (p4, _) = Cr3::read();
+    writeln!(
+        out,
+        "cr2={:016x} cr3={:016x}",
+        cr2.as_u64(),
+        p4.start_address().as_u64()
+    )
+}
+
+/// Displays the task or thread that was running when an exception occurred.
+struct Running;
+
+impl fmt::Display for Running {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if thread::on_boot_thread() {
+            let task = stats::with_current_task(|task| {
+                task.map(|(id, name)| write!(f, "task {} (id {})", name, id.as_u64()))
+            });
+            if let Some(result) = task {
+                return result;
+            }
+        }
+        match thread::try_current() {
+            Some((id, name)) => write!(f, "thread {} (id {})", name, id),
+            None => f.write_str("unknown thread"),
+        }
+    }
+}
+
+extern "C" {
+    /// Addresses of the entry stubs, indexed by vector.
+    static exception_entries: [u64; 32];
+}
+
+// The CPU aligns the stack to 16 bytes before pushing its frame, so with the
+// vector and error code the 15 register pushes leave it aligned for the call.
+global_asm!(
+    r#"
+.irp vector, 0,1,2,3,4,5,6,7,9,15,16,18,19,20,22,23,24,25,26,27,28,31
+exception_entry_\vector:
+    push 0
+    push \vector
+    jmp exception_common
+.endr
+
+.irp vector, 8,10,11,12,13,14,17,21,29,30
+exception_entry_\vector:
+    push \vector
+    jmp exception_common
+.endr
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    cld
+    mov rdi, rsp
+    call exception_inner
+    mov rsp, rax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+
+.pushsection .rodata
+.balign 8
+.global exception_entries
+exception_entries:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad exception_entry_\vector
+.endr
+.popsection
+"#
+);
+
+#[test_case]
+fn test_breakpoint_exception() {
+    x86_64::instructions::interrupts::int3();
+}
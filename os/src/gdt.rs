@@ -1,7 +1,9 @@
+use crate::memory::stack::KernelStack;
+use core::{cell::UnsafeCell, mem};
 use lazy_static::lazy_static;
 use x86_64::{
-    instructions::tables::load_tss,
-    registers::segmentation::{Segment, CS},
+    instructions::{interrupts, tables::load_tss},
+    registers::segmentation::{Segment, CS, DS, ES, SS},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
@@ -13,11 +15,19 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        // `sysret` expects user data directly before user code
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        // the descriptor only takes the address
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss() }));
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_code_selector,
+                user_data_selector,
                 tss_selector,
             },
         )
@@ -26,13 +36,28 @@ lazy_static! {
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
+
+/// The TSS, which changes after boot, so it's only accessed through the
+/// raw pointer `tss` returns.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// written only during boot and by the scheduler, with interrupts disabled
+unsafe impl Sync for Tss {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    // the static stacks are only used during boot, until `init_stacks`
+    // replaces them with guarded ones
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096 * 5;
@@ -41,14 +66,63 @@ lazy_static! {
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
             stack_start + STACK_SIZE
         };
-        tss
+        // stack used when an interrupt arrives in ring 3, until the scheduler
+        // installs the kernel stack of the running thread
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+fn tss() -> *mut TaskStateSegment {
+    TSS.0.get()
+}
+
 pub fn init() {
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Kernel selectors, as `(code, data)`.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// Selectors with RPL 3 for entering user mode, as `(code, data)`.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Moves the TSS stacks to kernel stacks with guard pages. Called once
+/// memory is set up.
+pub fn init_stacks() {
+    let double_fault = KernelStack::new(DOUBLE_FAULT_STACK_SIZE).expect("no double fault stack");
+    let privilege = KernelStack::new(PRIVILEGE_STACK_SIZE).expect("no privilege stack");
+    interrupts::without_interrupts(|| unsafe {
+        let tss = tss();
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault.top();
+        (*tss).privilege_stack_table[0] = privilege.top();
+    });
+    // used until shutdown
+    mem::forget(double_fault);
+    mem::forget(privilege);
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in ring 3.
+///
+/// Only called by the scheduler, with interrupts disabled. The CPU reads
+/// the field only on a privilege change, so updating it in place is fine.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*tss()).privilege_stack_table[0] = top };
+}
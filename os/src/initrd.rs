@@ -0,0 +1,68 @@
+//! Read-only archive of files linked into the kernel image, so programs can
+//! be run without a disk driver.
+//!
+//! The archive starts with the magic `SMOLRD01` and the number of files as a
+//! little-endian `u64`, followed by one 32-byte entry per file: the name,
+//! NUL-padded to 16 bytes, then its offset from the start of the archive
+//! and its size, both `u64`. The contents are assembled from `initrd.s`.
+
+use core::{arch::global_asm, ptr, str};
+
+global_asm!(include_str!("initrd.s"));
+
+extern "C" {
+    static initrd_start: u8;
+    static initrd_end: u8;
+}
+
+const MAGIC: &[u8; 8] = b"SMOLRD01";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 32;
+const NAME_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+fn archive() -> &'static [u8] {
+    unsafe {
+        let start = ptr::addr_of!(initrd_start);
+        let end = ptr::addr_of!(initrd_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+fn u64_at(data: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+/// All files in the archive.
+pub fn files() -> impl Iterator<Item = File> {
+    let archive = archive();
+    assert_eq!(&archive[..8], MAGIC, "initrd is corrupted");
+    let count = u64_at(archive, 8);
+    (0..count).map(move |index| {
+        let entry = &archive[HEADER_SIZE + index * ENTRY_SIZE..][..ENTRY_SIZE];
+        let name = &entry[..NAME_SIZE];
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(NAME_SIZE);
+        let offset = u64_at(entry, NAME_SIZE);
+        let size = u64_at(entry, NAME_SIZE + 8);
+        File {
+            name: str::from_utf8(&name[..name_len]).expect("initrd file name is not UTF-8"),
+            data: &archive[offset..offset + size],
+        }
+    })
+}
+
+/// Contents of the file called `name`.
+pub fn get(name: &str) -> Option<&'static [u8]> {
+    files().find(|file| file.name == name).map(|file| file.data)
+}
+
+#[test_case]
+fn test_lookup() {
+    assert!(get("hello").is_some());
+    assert!(get("missing").is_none());
+}
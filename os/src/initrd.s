@@ -0,0 +1,237 @@
+# Initial ramdisk linked into the kernel image, in the format read by
+# `initrd.rs`.
+#
+# The programs are tiny position independent ELF executables written
+# directly against the `syscall` ABI. Each consists of a file header, its
+# program headers and the code, with the first segment covering the whole
+# file at address 0.
+
+.pushsection .rodata.initrd, "a"
+.balign 8
+
+.global initrd_start
+.global initrd_end
+initrd_start:
+    .ascii "SMOLRD01"
+    .quad 4                             # number of files
+    # one entry per file: name padded to 16 bytes, offset, size
+    .ascii "hello"
+    .skip 11
+    .quad hello_elf - initrd_start
+    .quad hello_elf_end - hello_elf
+    .ascii "echo"
+    .skip 12
+    .quad echo_elf - initrd_start
+    .quad echo_elf_end - echo_elf
+    .ascii "fault"
+    .skip 11
+    .quad fault_elf - initrd_start
+    .quad fault_elf_end - fault_elf
+    .ascii "shared"
+    .skip 10
+    .quad shared_elf - initrd_start
+    .quad shared_elf_end - shared_elf
+
+# Greets, sleeps for 10 ms and exits with its privilege level, which lets
+# tests check that it really ran in ring 3.
+.balign 8
+hello_elf:
+    .byte 0x7f, 0x45, 0x4c, 0x46        # magic
+    .byte 2, 1, 1, 0                    # 64-bit, little endian, version 1, System V
+    .quad 0
+    .word 3                             # ET_DYN
+    .word 0x3e                          # x86_64
+    .long 1                             # version
+    .quad hello_main - hello_elf        # entry point
+    .quad 64                            # program header offset
+    .quad 0                             # section header offset
+    .long 0                             # flags
+    .word 64                            # file header size
+    .word 56                            # program header size
+    .word 1                             # program header count
+    .word 0, 0, 0                       # no section headers
+    # PT_LOAD, R + X
+    .long 1
+    .long 5
+    .quad 0                             # offset
+    .quad 0                             # virtual address
+    .quad 0                             # physical address
+    .quad hello_elf_end - hello_elf     # size in file
+    .quad hello_elf_end - hello_elf     # size in memory
+    .quad 0x1000                        # alignment
+hello_main:
+    lea rdi, [rip + hello_message]
+    lea rsi, [rip + hello_message_end]
+    sub rsi, rdi
+    mov eax, 0                          # write
+    int 0x80
+    mov eax, 3                          # sleep
+    mov edi, 10
+    int 0x80
+    mov ax, cs
+    movzx edi, ax
+    and edi, 3
+    mov eax, 2                          # exit
+    int 0x80
+hello_message:
+    .ascii "Hello from ring 3!\n"
+hello_message_end:
+hello_elf_end:
+
+# Writes back every key until Enter, then exits with 0. The key is passed to
+# `write` through a buffer in a zero-filled writable segment at 0x2000.
+.balign 8
+echo_elf:
+    .byte 0x7f, 0x45, 0x4c, 0x46
+    .byte 2, 1, 1, 0
+    .quad 0
+    .word 3
+    .word 0x3e
+    .long 1
+    .quad echo_main - echo_elf
+    .quad 64
+    .quad 0
+    .long 0
+    .word 64
+    .word 56
+    .word 2
+    .word 0, 0, 0
+    # PT_LOAD, R + X
+    .long 1
+    .long 5
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad echo_elf_end - echo_elf
+    .quad echo_elf_end - echo_elf
+    .quad 0x1000
+    # PT_LOAD, R + W, nothing but zeroes
+    .long 1
+    .long 6
+    .quad echo_elf_end - echo_elf
+    .quad 0x2000
+    .quad 0x2000
+    .quad 0
+    .quad 16
+    .quad 0x1000
+echo_main:
+    mov eax, 1                          # read_key
+    int 0x80
+    mov rbx, rax
+    lea rdi, [rip + echo_elf + 0x2000]
+    mov [rdi], al
+    mov esi, 1
+    mov eax, 0                          # write
+    int 0x80
+    cmp rbx, 10
+    jne echo_main
+    xor edi, edi
+    mov eax, 2                          # exit
+    int 0x80
+echo_elf_end:
+
+# Pushes onto its stack, which is mapped on demand, then writes to its own
+# code and gets killed.
+.balign 8
+fault_elf:
+    .byte 0x7f, 0x45, 0x4c, 0x46
+    .byte 2, 1, 1, 0
+    .quad 0
+    .word 3
+    .word 0x3e
+    .long 1
+    .quad fault_main - fault_elf
+    .quad 64
+    .quad 0
+    .long 0
+    .word 64
+    .word 56
+    .word 1
+    .word 0, 0, 0
+    # PT_LOAD, R + X
+    .long 1
+    .long 5
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad fault_elf_end - fault_elf
+    .quad fault_elf_end - fault_elf
+    .quad 0x1000
+fault_main:
+    push 42
+    pop rax
+    lea rdi, [rip + fault_main]
+    mov [rdi], al
+    xor edi, edi                        # not reached
+    mov eax, 2                          # exit
+    int 0x80
+fault_elf_end:
+
+# Has three segments sharing the first page: the code, a writable copy of
+# its message whose zero-filled part reaches into the next page, and a
+# zero-filled word after the end of the file. Writes to all of them and
+# exits with what it read back from the second page.
+.balign 8
+shared_elf:
+    .byte 0x7f, 0x45, 0x4c, 0x46
+    .byte 2, 1, 1, 0
+    .quad 0
+    .word 3
+    .word 0x3e
+    .long 1
+    .quad shared_main - shared_elf
+    .quad 64
+    .quad 0
+    .long 0
+    .word 64
+    .word 56
+    .word 3
+    .word 0, 0, 0
+    # PT_LOAD, R + X
+    .long 1
+    .long 5
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad shared_elf_end - shared_elf
+    .quad shared_elf_end - shared_elf
+    .quad 0x1000
+    # PT_LOAD, R + W, the message again, then zeroes up to 0x2000
+    .long 1
+    .long 6
+    .quad shared_message - shared_elf
+    .quad shared_message - shared_elf
+    .quad shared_message - shared_elf
+    .quad shared_message_end - shared_message
+    .quad 0x2000 - (shared_message - shared_elf)
+    .quad 0x1000
+    # PT_LOAD, R + W, nothing but zeroes
+    .long 1
+    .long 6
+    .quad 0
+    .quad shared_elf_end - shared_elf
+    .quad shared_elf_end - shared_elf
+    .quad 0
+    .quad 8
+    .quad 0x1000
+shared_main:
+    lea rdi, [rip + shared_message]
+    mov byte ptr [rdi], 0x53            # 'S'
+    lea rsi, [rip + shared_message_end]
+    sub rsi, rdi
+    mov eax, 0                          # write
+    int 0x80
+    lea rdi, [rip + shared_elf_end]
+    mov qword ptr [rdi], 1
+    lea rdi, [rip + shared_elf + 0x1800]
+    mov qword ptr [rdi], 42
+    mov rdi, [rdi]
+    mov eax, 2                          # exit
+    int 0x80
+shared_message:
+    .ascii "shared pages\n"
+shared_message_end:
+shared_elf_end:
+
+initrd_end:
+.popsection
@@ -1,9 +1,9 @@
-use crate::println;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 use lazy_static::lazy_static;
 
-use crate::{gdt, hlt_loop};
+use crate::{apic, exceptions, irq, println, syscall, thread};
 use pic8259::ChainedPics;
 use spin;
 
@@ -16,15 +16,18 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        exceptions::install(&mut idt);
         unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            // these stubs save all registers so the scheduler can switch threads
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(thread::timer_interrupt_entry as usize as u64));
+            idt[thread::YIELD_VECTOR as usize]
+                .set_handler_addr(VirtAddr::new(thread::yield_interrupt_entry as usize as u64));
+            idt[syscall::SYSCALL_VECTOR as usize]
+                .set_handler_addr(VirtAddr::new(thread::syscall_interrupt_entry as usize as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        irq::install(&mut idt);
         idt
     };
 }
@@ -33,31 +36,24 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-#[test_case]
-fn test_breakpoint_exception() {
-    x86_64::instructions::interrupts::int3();
+/// Moves interrupt delivery to the APICs, keeping the PICs if that fails.
+pub fn init_apic() {
+    match apic::init() {
+        Ok(()) => irq::restore_masks(),
+        Err(err) => println!("APIC unavailable ({:?}), using the PIC", err),
+    }
 }
 
+/// Interrupts with handlers of their own. Other hardware interrupts go
+/// through `irq`.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -66,37 +62,26 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // print!(".");
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Signals the end of the interrupt on `vector` to whichever controller
+/// delivered it.
+pub(crate) fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
+/// Called by `thread::timer_interrupt_entry` with the stack pointer to the
+/// saved registers; returns the stack pointer to resume from.
+#[no_mangle]
+extern "C" fn timer_interrupt_inner(rsp: u64) -> u64 {
+    crate::time::tick();
+    crate::task::timer::on_tick();
+    crate::task::stats::on_tick();
+    irq::on_timer();
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+    end_of_interrupt(InterruptIndex::Timer.as_u8());
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    thread::preempt(rsp)
 }
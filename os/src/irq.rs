@@ -0,0 +1,362 @@
+//! Hardware interrupt lines and the handlers drivers register for them.
+//!
+//! The 16 ISA lines are delivered to vectors `PIC_1_OFFSET + irq`, by the
+//! PICs or, once `apic::init` succeeded, by the I/O APIC. Line 0 is the
+//! timer, which the scheduler's own entry stub handles, and line 2 cascades
+//! the PICs, so neither can be registered for.
+//!
+//! A line can be shared by up to `MAX_SHARED` handlers, which are all called
+//! on every interrupt: a handler returns whether its device raised it. The
+//! end of interrupt is signalled after the last one, and a line is masked
+//! while no handler is registered for it.
+//!
+//! The handler table is fixed-size, so registering never allocates, and it's
+//! locked with interrupts disabled. Handlers run in interrupt context and
+//! must not allocate or take locks held with interrupts enabled.
+
+use crate::{
+    apic,
+    interrupts::{self, PICS, PIC_1_OFFSET},
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts::without_interrupts, port::Port},
+    structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame},
+};
+
+pub const IRQ_LINES: usize = 16;
+/// Handlers that can share a line.
+pub const MAX_SHARED: usize = 4;
+pub const TIMER_IRQ: u8 = 0;
+const CASCADE_IRQ: u8 = 2;
+
+/// Handles an interrupt on line `irq`; returns `true` if it came from the
+/// handler's device.
+pub type Handler = fn(irq: u8) -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// There's no such line.
+    BadLine,
+    /// The line is the timer or the PIC cascade.
+    Reserved,
+    /// The line already has `MAX_SHARED` handlers.
+    Full,
+}
+
+/// A registered handler, to be passed to `unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registration {
+    irq: u8,
+    id: u64,
+}
+
+impl Registration {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    id: u64,
+    name: &'static str,
+    handler: Handler,
+}
+
+type Line = [Option<Entry>; MAX_SHARED];
+
+static LINES: Mutex<[Line; IRQ_LINES]> = Mutex::new([[None; MAX_SHARED]; IRQ_LINES]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+static UNHANDLED: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupt vector of line `irq`.
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Adds `handler` to line `irq`, unmasking the line if it's the first one.
+/// `name` is shown by the `irqs` command.
+pub fn register(irq: u8, name: &'static str, handler: Handler) -> Result<Registration, IrqError> {
+    if usize::from(irq) >= IRQ_LINES {
+        return Err(IrqError::BadLine);
+    }
+    if irq == TIMER_IRQ || irq == CASCADE_IRQ {
+        return Err(IrqError::Reserved);
+    }
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let line = &mut lines[usize::from(irq)];
+        let was_empty = line.iter().all(Option::is_none);
+        let slot = line
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::Full)?;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        *slot = Some(Entry { id, name, handler });
+        if was_empty {
+            set_masked(irq, false);
+        }
+        Ok(Registration { irq, id })
+    })
+}
+
+/// Removes a handler, masking its line if it was the last one. Does nothing
+/// if it was removed already.
+pub fn unregister(registration: Registration) {
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let line = &mut lines[usize::from(registration.irq)];
+        for slot in line.iter_mut() {
+            if matches!(slot, Some(entry) if entry.id == registration.id) {
+                *slot = None;
+            }
+        }
+        if line.iter().all(Option::is_none) {
+            set_masked(registration.irq, true);
+        }
+    });
+}
+
+/// Masks or unmasks line `irq` in whichever controller delivers it. Called
+/// with interrupts disabled.
+fn set_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        apic::route_isa_irq(irq, vector(irq), masked);
+        return;
+    }
+    let mut pics = PICS.lock();
+    unsafe {
+        let mut masks = pics.read_masks();
+        let (pic, bit) = (usize::from(irq / 8), 1 << (irq % 8));
+        if masked {
+            masks[pic] |= bit;
+        } else {
+            masks[pic] &= !bit;
+        }
+        pics.write_masks(masks[0], masks[1]);
+    }
+}
+
+/// Masks every PIC line but the timer and the cascade, until handlers are
+/// registered. Called after the PICs are initialized.
+pub fn init() {
+    without_interrupts(|| unsafe {
+        let unmasked = 1 << TIMER_IRQ | 1 << CASCADE_IRQ;
+        PICS.lock().write_masks(!unmasked, 0xff);
+    });
+}
+
+/// Applies the masks of all lines again, after `apic::init` moved them to
+/// the I/O APIC.
+pub(crate) fn restore_masks() {
+    without_interrupts(|| {
+        let lines = LINES.lock();
+        for (irq, line) in lines.iter().enumerate() {
+            let irq = irq as u8;
+            if irq != TIMER_IRQ && irq != CASCADE_IRQ {
+                set_masked(irq, line.iter().all(Option::is_none));
+            }
+        }
+    });
+}
+
+/// Called from the timer interrupt handler, which handles line 0 itself.
+pub(crate) fn on_timer() {
+    COUNTS[usize::from(TIMER_IRQ)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns `true` if the PIC delivering line `irq` is servicing it.
+fn pic_in_service(irq: u8) -> bool {
+    const READ_ISR: u8 = 0x0b;
+    let port = if irq < 8 { 0x20 } else { 0xa0 };
+    let mut command = Port::<u8>::new(port);
+    unsafe {
+        command.write(READ_ISR);
+        command.read() & 1 << (irq % 8) != 0
+    }
+}
+
+/// The PICs raise their lowest priority line, 7 or 15, when a request goes
+/// away before it's acknowledged. Such an interrupt isn't in service and
+/// gets no end of interrupt, except from the master for the cascade.
+fn is_spurious(irq: u8) -> bool {
+    if apic::is_enabled() || (irq != 7 && irq != 15) || pic_in_service(irq) {
+        return false;
+    }
+    if irq == 15 {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector(CASCADE_IRQ)) };
+    }
+    true
+}
+
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    COUNTS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    // a copy, so handlers run without the lock
+    let line = LINES.lock()[usize::from(irq)];
+    let mut handled = false;
+    for entry in line.iter().flatten() {
+        handled |= (entry.handler)(irq);
+    }
+    if !handled {
+        UNHANDLED[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    }
+    interrupts::end_of_interrupt(vector(irq));
+}
+
+macro_rules! line_entries {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// Entry points of lines 1 to 15.
+        const LINE_ENTRIES: [HandlerFunc; IRQ_LINES - 1] = [$($name),*];
+    };
+}
+
+line_entries! {
+    1 => irq_1, 2 => irq_2, 3 => irq_3, 4 => irq_4, 5 => irq_5,
+    6 => irq_6, 7 => irq_7, 8 => irq_8, 9 => irq_9, 10 => irq_10,
+    11 => irq_11, 12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15,
+}
+
+/// The local APIC raises spurious interrupts without expecting an EOI.
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Sets the IDT entries of lines 1 to 15 and of the local APIC's spurious
+/// interrupts.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    for (irq, &entry) in (1..).zip(LINE_ENTRIES.iter()) {
+        idt[usize::from(vector(irq))].set_handler_fn(entry);
+    }
+    idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
+}
+
+/// Interrupts and handlers of a line.
+#[derive(Debug, Clone)]
+pub struct IrqStats {
+    pub irq: u8,
+    pub count: u64,
+    /// Interrupts none of the handlers claimed.
+    pub unhandled: u64,
+    pub handlers: Vec<&'static str>,
+}
+
+/// Statistics of the lines that have handlers or have fired.
+pub fn stats() -> Vec<IrqStats> {
+    let lines = without_interrupts(|| *LINES.lock());
+    (0..IRQ_LINES)
+        .map(|irq| {
+            let mut handlers: Vec<_> = lines[irq].iter().flatten().map(|e| e.name).collect();
+            if irq == usize::from(TIMER_IRQ) {
+                handlers.push("timer");
+            }
+            IrqStats {
+                irq: irq as u8,
+                count: COUNTS[irq].load(Ordering::Relaxed),
+                unhandled: UNHANDLED[irq].load(Ordering::Relaxed),
+                handlers,
+            }
+        })
+        .filter(|line| line.count > 0 || !line.handlers.is_empty())
+        .collect()
+}
+
+/// Spurious interrupts from the PICs or the local APIC.
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Raises line 5 in software, for tests; `int` takes a literal vector.
+#[cfg(test)]
+fn raise_irq_5() {
+    assert_eq!(vector(5), 37);
+    unsafe { core::arch::asm!("int 37") };
+}
+
+#[cfg(test)]
+fn line_counts(irq: u8) -> (u64, u64) {
+    let irq = usize::from(irq);
+    (
+        COUNTS[irq].load(Ordering::Relaxed),
+        UNHANDLED[irq].load(Ordering::Relaxed),
+    )
+}
+
+#[test_case]
+fn test_shared_handlers() {
+    use core::sync::atomic::AtomicUsize;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn claims(_irq: u8) -> bool {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+    fn ignores(_irq: u8) -> bool {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    let first = register(5, "first", claims).unwrap();
+    let second = register(5, "second", ignores).unwrap();
+    let (count, unhandled) = line_counts(5);
+    raise_irq_5();
+    // every handler of a shared line runs
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+    assert_eq!(line_counts(5), (count + 1, unhandled));
+
+    unregister(first);
+    raise_irq_5();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+    assert_eq!(line_counts(5), (count + 2, unhandled + 1));
+    unregister(second);
+    assert!(stats()
+        .iter()
+        .all(|line| line.irq != 5 || line.handlers.is_empty()));
+}
+
+#[test_case]
+fn test_register_errors() {
+    fn claims(_irq: u8) -> bool {
+        true
+    }
+
+    assert_eq!(register(0, "timer", claims), Err(IrqError::Reserved));
+    assert_eq!(register(2, "cascade", claims), Err(IrqError::Reserved));
+    assert_eq!(register(16, "none", claims), Err(IrqError::BadLine));
+
+    let registrations: Vec<_> = (0..MAX_SHARED)
+        .map(|_| register(6, "test", claims).unwrap())
+        .collect();
+    assert_eq!(register(6, "test", claims), Err(IrqError::Full));
+    for registration in registrations {
+        unregister(registration);
+    }
+    unregister(register(6, "test", claims).unwrap());
+}
+
+#[test_case]
+fn test_timer_is_counted() {
+    let ticks = line_counts(TIMER_IRQ).0;
+    crate::time::pit_delay(5);
+    assert!(line_counts(TIMER_IRQ).0 > ticks);
+    assert!(stats()
+        .iter()
+        .any(|line| line.irq == TIMER_IRQ && line.handlers == ["timer"]));
+}
@@ -4,7 +4,14 @@ use alloc::{
     vec::Vec,
 };
 use futures_util::stream::StreamExt;
-use os::{print, println, smol_script, task::keyboard::ScancodeStream, vga_buffer::Color};
+use os::{
+    allocator, apic, initrd, irq, memory, print, println,
+    process::{self, SpawnError},
+    smol_script,
+    task::{executor, keyboard::ScancodeStream, stats, timer, TaskId, TaskInfo, TaskState},
+    time,
+    vga_buffer::Color,
+};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
 pub async fn handle_main() {
@@ -22,6 +29,9 @@ pub async fn handle_main() {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
                     DecodedKey::Unicode(character) => {
+                        if process::send_key(character) {
+                            continue;
+                        }
                         if type_mode {
                             if let Some(file) = files.last_mut() {
                                 if character == '\x1b' {
@@ -76,12 +86,143 @@ fn execute(command: &str, type_mode: &mut bool, files: &mut Vec<File>, user_name
             println!("Made in Rust");
             println!("Made by: Bunch-of-cells, Catt & SnmLogic");
         }
+        ["uptime"] => {
+            let uptime = time::uptime();
+            let secs = uptime.as_secs();
+            println!(
+                "up {:02}:{:02}:{:02}.{:03} ({} ticks)",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60,
+                uptime.subsec_millis(),
+                time::ticks()
+            );
+        }
+        ["timer", ms] => match ms.parse::<u64>() {
+            Ok(ms) => {
+                executor::spawn_named("timer", async move {
+                    timer::sleep(ms).await;
+                    println!(FG: Color::Yellow, "\ntimer: {} ms elapsed", ms);
+                });
+            }
+            Err(_) => println!("Invalid input"),
+        },
+        ["ps"] => {
+            let now = time::ticks();
+            println!(
+                "{:>5}  {:<16} {:<8} {:<12} {:>8} {:>10}",
+                "ID", "NAME", "STATE", "PRIORITY", "POLLS", "AGE (ms)"
+            );
+            for task in executor::tasks() {
+                println!(
+                    "{:>5}  {:<16} {:<8} {:<12} {:>8} {:>10}",
+                    task.id,
+                    task.name,
+                    match task.state {
+                        TaskState::Ready => "ready",
+                        TaskState::Pending => "pending",
+                    },
+                    task.priority,
+                    task.polls,
+                    time::ticks_to_ms(now - task.created)
+                );
+            }
+        }
+        ["kill", id] => match id.parse::<u64>() {
+            Ok(id) => {
+                if !executor::abort(TaskId::from(id)) {
+                    println!("No such task");
+                }
+            }
+            Err(_) => println!("Invalid input"),
+        },
+        ["exec", program] => match process::exec(program) {
+            Ok(process) => {
+                process::set_foreground(Some(&process));
+                executor::spawn_named("wait", async move {
+                    let code = process.wait().await;
+                    println!(
+                        FG: Color::Yellow,
+                        "\n{} (pid {}) exited with code {}",
+                        process.name(),
+                        process.pid(),
+                        code
+                    );
+                });
+            }
+            Err(SpawnError::UnknownProgram) => {
+                println!("Unknown program, try one of:");
+                for file in initrd::files() {
+                    println!("     {}", file.name);
+                }
+            }
+            Err(err) => println!("exec failed: {:?}", err),
+        },
+        ["frames"] => {
+            let stats = memory::frame_stats();
+            println!(
+                "{} of {} frames free ({} KiB of {} KiB)",
+                stats.free_frames,
+                stats.total_frames,
+                stats.free_frames * 4,
+                stats.total_frames * 4
+            );
+            println!("largest free block: {} frames", stats.largest_free_block());
+        }
+        ["meminfo"] | ["free"] => meminfo(),
+        ["slabs"] => {
+            println!(FG: Color::LightCyan, "cache            size  in use    free   slabs");
+            for cache in allocator::slab::cache_stats() {
+                println!(
+                    "{:<14} {:>6} {:>7} {:>7} {:>7}",
+                    cache.name,
+                    cache.object_size,
+                    cache.in_use,
+                    cache.free_objects(),
+                    cache.slabs
+                );
+            }
+        }
+        ["irqs"] => {
+            let controller = if apic::is_enabled() {
+                "I/O APIC"
+            } else {
+                "8259 PIC"
+            };
+            println!("interrupt controller: {}", controller);
+            println!(FG: Color::LightCyan, "irq      count  unhandled  handlers");
+            for line in irq::stats() {
+                println!(
+                    "{:>3} {:>10} {:>10}  {}",
+                    line.irq,
+                    line.count,
+                    line.unhandled,
+                    line.handlers.join(", ")
+                );
+            }
+            println!("spurious: {}", irq::spurious_count());
+        }
+        ["top"] => top(10),
+        ["top", refreshes] => match refreshes.parse::<u32>() {
+            Ok(refreshes) => top(refreshes),
+            Err(_) => println!("Invalid input"),
+        },
         ["help"] => {
             println!("Available commands:");
             println!("     clear");
             println!("     shut-down");
             println!("     os-info");
             println!("     help");
+            println!("     uptime");
+            println!("     timer");
+            println!("     ps");
+            println!("     kill");
+            println!("     top");
+            println!("     exec");
+            println!("     frames");
+            println!("     meminfo");
+            println!("     slabs");
+            println!("     irqs");
             println!("     type");
             println!("     ls");
             println!("     save");
@@ -197,6 +338,112 @@ fn execute(command: &str, type_mode: &mut bool, files: &mut Vec<File>, user_name
     };
 }
 
+/// Prints heap and physical memory usage.
+fn meminfo() {
+    let heap = allocator::heap_stats();
+    let frames = memory::frame_stats();
+    println!(FG: Color::LightCyan, "heap ({} allocator)", heap.allocator);
+    println!(
+        "  mapped: {} KiB of {} KiB, {} bytes used, {} bytes free",
+        heap.heap_size / 1024,
+        heap.heap_limit / 1024,
+        heap.used,
+        heap.free()
+    );
+    println!(
+        "  allocated: {} bytes in {} allocations (peak {} bytes)",
+        heap.alloc.allocated,
+        heap.alloc.live(),
+        heap.alloc.peak
+    );
+    println!(
+        "  total: {} allocations, {} frees, {} failed",
+        heap.alloc.allocations, heap.alloc.deallocations, heap.alloc.failures
+    );
+    println!(
+        "  fragmentation: {} bytes cached in free blocks, {} bytes overhead",
+        heap.cached(),
+        heap.internal_fragmentation()
+    );
+    if let Some(blocks) = heap.blocks {
+        println!("  fallback: {} allocations", blocks.fallback_allocations);
+        println!(FG: Color::LightCyan, "  block size   in use     free");
+        for class in blocks.size_classes.iter() {
+            println!(
+                "  {:>10} {:>8} {:>8}",
+                class.block_size, class.in_use, class.free
+            );
+        }
+    }
+    println!(FG: Color::LightCyan, "frames");
+    println!(
+        "  used: {} KiB, free: {} KiB of {} KiB",
+        frames.used_frames() * 4,
+        frames.free_frames * 4,
+        frames.total_frames * 4
+    );
+    println!(
+        "  largest free block: {} frames",
+        frames.largest_free_block()
+    );
+}
+
+/// Spawns a task that redraws CPU load and the busiest tasks once a second.
+fn top(refreshes: u32) {
+    executor::spawn_named("top", async move {
+        let mut interval = timer::interval(1000);
+        let mut last_cpu = stats::cpu_stats();
+        let mut last_tasks = executor::tasks();
+
+        for _ in 0..refreshes {
+            interval.next().await;
+            let cpu = stats::cpu_stats();
+            let tasks = executor::tasks();
+
+            // CPU ticks used by each task since the last refresh
+            let mut rows = tasks
+                .iter()
+                .map(|task| {
+                    let before = last_tasks
+                        .iter()
+                        .find(|t| t.id == task.id)
+                        .map_or(0, |t| t.cpu_ticks);
+                    (task.cpu_ticks - before, task)
+                })
+                .collect::<Vec<(u64, &TaskInfo)>>();
+            rows.sort_by_key(|row| core::cmp::Reverse(row.0));
+
+            let interval_ticks =
+                (cpu.busy_ticks + cpu.idle_ticks) - (last_cpu.busy_ticks + last_cpu.idle_ticks);
+            println!("\0");
+            println!(
+                FG: Color::LightCyan,
+                "load: {}%   tasks: {}   uptime: {} s",
+                cpu.load_since(&last_cpu),
+                tasks.len(),
+                time::uptime().as_secs()
+            );
+            println!(
+                "{:>5}  {:<16} {:<12} {:>6} {:>10}",
+                "ID", "NAME", "PRIORITY", "CPU%", "CPU (ms)"
+            );
+            for (ticks, task) in rows.iter().take(15) {
+                println!(
+                    "{:>5}  {:<16} {:<12} {:>6} {:>10}",
+                    task.id,
+                    task.name,
+                    task.priority,
+                    ticks * 100 / interval_ticks.max(1),
+                    time::ticks_to_ms(task.cpu_ticks)
+                );
+            }
+
+            last_cpu = cpu;
+            last_tasks = tasks;
+        }
+    });
+}
+
 struct File {
     name: Option<String>,
     content: String,
@@ -10,13 +10,24 @@
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod elf;
+pub mod exceptions;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
+pub mod irq;
 pub mod memory;
+pub mod process;
 pub mod serial;
 pub mod smol_script;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
 pub mod vga_buffer;
 
 extern crate alloc;
@@ -25,7 +36,7 @@ extern crate alloc;
 use bootloader::entry_point;
 
 use bootloader::BootInfo;
-use memory::BootInfoFrameAllocator;
+use memory::GlobalFrameAllocator;
 use x86_64::VirtAddr;
 
 use crate::vga_buffer::Color;
@@ -65,11 +76,20 @@ pub fn init(boot_info: &'static BootInfo) {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    irq::init();
+    task::keyboard::init();
+    time::init();
     x86_64::instructions::interrupts::enable();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    unsafe { memory::frame_allocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    memory::install(mapper);
+    memory::vma::init();
+    interrupts::init_apic();
+    gdt::init_stacks();
+    thread::init();
 }
 
 pub fn init_screens() {
@@ -112,6 +132,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    let _ = backtrace::write_current(&mut *serial::force_lock());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -8,7 +8,7 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 extern crate alloc;
 
-use os::task::{executor::Executor, Task};
+use os::task::{executor::Executor, Priority, Task};
 
 mod kernel;
 
@@ -19,14 +19,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     os::init_screens();
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(kernel::handle_main()));
+    executor
+        .spawn(Task::named("shell", kernel::handle_main()).with_priority(Priority::Interactive));
     executor.run();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    os::println!("{}", info);
+    use core::fmt::Write;
+
+    // keep other threads from drawing over the crash screen
+    x86_64::instructions::interrupts::disable();
+    let mut log = os::exceptions::CrashLog::new();
+    let _ = writeln!(log, "{}", info);
+    let _ = os::backtrace::write_current(&mut log);
     os::hlt_loop();
 }
 
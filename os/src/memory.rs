@@ -2,6 +2,19 @@ use x86_64::{structures::paging::PageTable, PhysAddr, VirtAddr};
 
 use x86_64::structures::paging::OffsetPageTable;
 
+pub mod address_space;
+pub mod frame_allocator;
+pub mod stack;
+pub mod vma;
+pub use address_space::AddressSpace;
+pub use frame_allocator::{frame_stats, FrameStats, GlobalFrameAllocator};
+
+/// Start of the lower half region reserved for user mappings. The first
+/// 512 GiB hold the kernel image and the bootloader's identity mappings.
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+/// End of the lower half, and of user space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -9,6 +22,7 @@ use x86_64::structures::paging::OffsetPageTable;
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -74,7 +88,9 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PhysFrame, Size4KiB,
+};
 
 /// Creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
@@ -94,47 +110,226 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::MemoryRegionType;
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, page::PageRangeInclusive};
+
+/// Page table mapper of the running kernel, along with a handle to the
+/// global frame allocator.
+pub struct Memory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: GlobalFrameAllocator,
+}
+
+static MEMORY: spin::Mutex<Option<Memory>> = spin::Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Makes the mapper available through `with_memory`.
+///
+/// The active page table becomes the kernel's, which kernel threads run on
+/// and every `AddressSpace` copies its kernel half from.
+pub fn install(mut mapper: OffsetPageTable<'static>) {
+    use x86_64::registers::control::Cr3;
+
+    let mut user_entries = usize::from(VirtAddr::new(USER_SPACE_START).p4_index())
+        ..=usize::from(VirtAddr::new(USER_SPACE_END - 1).p4_index());
+    assert!(
+        user_entries.all(|index| mapper.level_4_table()[index].is_unused()),
+        "kernel mappings in user space, check the bootloader config"
+    );
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    *MEMORY.lock() = Some(Memory {
+        mapper,
+        frame_allocator: GlobalFrameAllocator,
+    });
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+/// Runs `f` with exclusive access to the kernel's mapper.
+///
+/// Must not be called from interrupt handlers, since the interrupted code
+/// might hold the lock.
+pub fn with_memory<R>(f: impl FnOnce(&mut Memory) -> R) -> R {
+    f(MEMORY.lock().as_mut().expect("memory not initialized"))
+}
+
+/// Maps fresh frames at `pages` in the kernel's page table, for the heap.
+///
+/// The heap's level 4 entry already exists when the first address space is
+/// created, so every address space sees the new pages.
+///
+/// On failure, the pages mapped so far are unmapped and their frames freed,
+/// so the heap can try to grow from the same address again.
+///
+/// Doesn't take the `with_memory` lock, since the heap grows from inside
+/// allocations, which may happen while it's held. Unsafe because the caller
+/// must make sure nothing else maps pages in the same region.
+pub(crate) unsafe fn map_heap_pages(pages: PageRangeInclusive) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let p4 = &mut *phys_to_virt(kernel_page_table().start_address()).as_mut_ptr();
+    let mut mapper = OffsetPageTable::new(p4, physical_memory_offset());
+    let mut frame_allocator = GlobalFrameAllocator;
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let result = pages.into_iter().try_for_each(|page| {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        match mapper.map_to(page, frame, flags, &mut frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                frame_allocator.deallocate_frame(frame);
+                Err(err)
+            }
+        }
+    });
+    if result.is_err() {
+        for page in pages {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    frame_allocator.deallocate_frame(frame);
+                }
+                // pages are mapped in order, so the rest weren't mapped
+                Err(_) => break,
+            }
         }
     }
+    result
+}
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
+/// Start of the kernel region device registers are mapped to by `map_mmio`.
+const MMIO_START: u64 = 0xFFFF_C000_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Maps the `size` bytes of device registers at `addr` uncached and returns
+/// their virtual address. The mapping is permanent.
+///
+/// The region's level 4 entry is created by the first call, so that must
+/// happen during boot, before any address space copies the kernel half.
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let first = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last = PhysFrame::containing_address(addr + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first, last);
+    let len = (last.start_address() - first.start_address()) + 4096;
+    let start = NEXT_MMIO.fetch_add(len, Ordering::Relaxed);
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::NO_EXECUTE;
+    with_memory(|memory| {
+        for (i, frame) in frames.enumerate() {
+            let page = Page::containing_address(VirtAddr::new(start + i as u64 * 4096));
+            unsafe {
+                memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)?
+                    .flush();
+            }
+        }
+        Ok(VirtAddr::new(start) + (addr - first.start_address()))
+    })
 }
 
-pub struct EmptyFrameAllocator;
+/// Level 4 table of the kernel's own address space.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Virtual address at which the given physical address is accessible.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Returns `true` if every page in `start..start + len` is mapped and
+/// accessible from ring 3 (and writable, if `write` is set).
+///
+/// Walks the active page table without taking any locks, so it can be used
+/// from the syscall handler.
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let end = match start.as_u64().checked_add(len) {
+        Some(end) if start.as_u64() >= USER_SPACE_START && end <= USER_SPACE_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
     }
+
+    let mut required = Flags::PRESENT | Flags::USER_ACCESSIBLE;
+    if write {
+        required |= Flags::WRITABLE;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        let (mut frame, _) = Cr3::read();
+        let indexes = [
+            page.p4_index(),
+            page.p3_index(),
+            page.p2_index(),
+            page.p1_index(),
+        ];
+        for &index in &indexes {
+            let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+            let entry = &table[index];
+            if !entry.flags().contains(required) {
+                return false;
+            }
+            frame = match entry.frame() {
+                Ok(frame) => frame,
+                Err(_) => return false,
+            };
+        }
+        true
+    })
 }
 
-unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        None
+/// Returns `true` if `addr` is mapped in the active page table.
+///
+/// Walks the page table without taking any locks and doesn't panic, so it
+/// can be used while reporting a crash.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    if physical_memory_offset().as_u64() == 0 {
+        return false;
+    }
+    let (mut frame, _) = Cr3::read();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for &index in &indexes {
+        let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+        let entry = &table[index];
+        if !entry.flags().contains(Flags::PRESENT) {
+            return false;
+        }
+        if entry.flags().contains(Flags::HUGE_PAGE) {
+            return true;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
     }
+    true
 }
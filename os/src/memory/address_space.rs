@@ -0,0 +1,345 @@
+use super::{
+    kernel_page_table, phys_to_virt, physical_memory_offset,
+    vma::{self, VmaError},
+    GlobalFrameAllocator, USER_SPACE_END, USER_SPACE_START,
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{
+            FlagUpdateError, MapToError, MappedFrame, MapperFlush, TranslateResult, UnmapError,
+        },
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// A level 4 page table of its own, sharing the kernel's half of the
+/// address space with every other one.
+///
+/// The P4 entries outside the user half are copied from the kernel's table
+/// when the address space is created, so later kernel mappings are only seen
+/// everywhere if they go below P4 entries that already existed back then.
+///
+/// Changes are flushed from the TLB only while the address space is active;
+/// otherwise `activate` takes care of it by reloading CR3.
+///
+/// Every frame mapped in the user half belongs to the address space: it is
+/// freed when unmapped, and dropping the address space frees all of them
+/// along with the page tables. That includes the frames mapped on demand in
+/// its lazy areas, see `map_lazy`.
+pub struct AddressSpace {
+    p4: PhysFrame,
+}
+
+fn table(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+fn is_user_entry(index: usize) -> bool {
+    let start = VirtAddr::new(USER_SPACE_START).p4_index();
+    let end = VirtAddr::new(USER_SPACE_END - 1).p4_index();
+    (usize::from(start)..=usize::from(end)).contains(&index)
+}
+
+/// Whether `pages` is a non-empty range in the user half.
+fn is_user_range(pages: &PageRangeInclusive) -> bool {
+    let user_space = USER_SPACE_START..USER_SPACE_END;
+    user_space.contains(&pages.start.start_address().as_u64())
+        && user_space.contains(&pages.end.start_address().as_u64())
+        && pages.start <= pages.end
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel's mappings and an empty
+    /// user half.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<Self> {
+        let p4 = frame_allocator.allocate_frame()?;
+        let kernel = unsafe { &*table(kernel_page_table()) };
+        let new = unsafe { &mut *table(p4) };
+        for (index, entry) in new.iter_mut().enumerate() {
+            if is_user_entry(index) {
+                entry.set_unused();
+            } else {
+                *entry = kernel[index].clone();
+            }
+        }
+        Some(AddressSpace { p4 })
+    }
+
+    /// Frame holding the level 4 table, as loaded into CR3.
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4
+    }
+
+    /// Switches to this address space by writing CR3, which also flushes
+    /// all non-global TLB entries.
+    ///
+    /// Unsafe because the caller must make sure the code, stack and data it
+    /// keeps using are mapped here too, and that the address space isn't
+    /// dropped while still active.
+    pub unsafe fn activate(&self) {
+        let (current, flags) = Cr3::read();
+        if current != self.p4 {
+            Cr3::write(self.p4, flags);
+        }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(&mut *table(self.p4), physical_memory_offset()) }
+    }
+
+    fn flush(&self, flush: MapperFlush<Size4KiB>) {
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+
+    /// Maps every page in `pages` to a fresh, zeroed frame.
+    ///
+    /// Panics if `pages` isn't in the user half, like `unmap` and `protect`.
+    pub fn map<A>(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<(), MapToError<Size4KiB>>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        assert!(is_user_range(&pages), "mapping outside the user half");
+        for page in pages {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                core::ptr::write_bytes(table(frame) as *mut u8, 0, 4096);
+                if let Err(err) = self.map_to(page, frame, flags, frame_allocator) {
+                    frame_allocator.deallocate_frame(frame);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers `pages` in the user half as a lazy area named `name`: each
+    /// page is mapped to a fresh, zeroed frame with `flags` when it's first
+    /// accessed. None of the pages may be mapped or in another lazy area.
+    pub fn map_lazy(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+        name: &'static str,
+    ) -> Result<(), VmaError> {
+        if !is_user_range(&pages) {
+            return Err(VmaError::BadRange);
+        }
+        for page in pages {
+            if self.translate(page.start_address()).is_some() {
+                return Err(VmaError::Overlap);
+            }
+        }
+        vma::register_user(self.p4, pages, flags, name)
+    }
+
+    /// Maps `page` to `frame`, which then belongs to the address space.
+    /// Panics if `page` isn't in the user half.
+    ///
+    /// Unsafe for the same reasons as `Mapper::map_to`: the frame must not be
+    /// in use for anything else.
+    pub unsafe fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            is_user_range(&Page::range_inclusive(page, page)),
+            "mapping outside the user half"
+        );
+        let flush = self.mapper().map_to(page, frame, flags, frame_allocator)?;
+        self.flush(flush);
+        Ok(())
+    }
+
+    /// Unmaps every page in `pages` and frees its frame, skipping the pages
+    /// that aren't mapped.
+    pub fn unmap(
+        &mut self,
+        pages: PageRangeInclusive,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), UnmapError> {
+        assert!(is_user_range(&pages), "unmapping outside the user half");
+        for page in pages {
+            match self.mapper().unmap(page) {
+                Ok((frame, flush)) => {
+                    self.flush(flush);
+                    unsafe { frame_deallocator.deallocate_frame(frame) };
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the flags of every page in `pages`, which must all be mapped.
+    pub fn protect(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        assert!(is_user_range(&pages), "protecting outside the user half");
+        for page in pages {
+            let flush = unsafe { self.mapper().update_flags(page, flags)? };
+            self.flush(flush);
+        }
+        Ok(())
+    }
+
+    /// Frame and flags `page` is mapped with, if it's mapped.
+    pub fn lookup(&self, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        let mapper =
+            unsafe { OffsetPageTable::new(&mut *table(self.p4), physical_memory_offset()) };
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => Some((frame, flags)),
+            _ => None,
+        }
+    }
+
+    /// Physical address `addr` is mapped to, if any.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let mapper =
+            unsafe { OffsetPageTable::new(&mut *table(self.p4), physical_memory_offset()) };
+        mapper.translate_addr(addr)
+    }
+}
+
+/// Frees `frame` and, for page tables (`level` > 0), everything mapped
+/// below it.
+unsafe fn free_tree(
+    frame: PhysFrame,
+    level: u8,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if level > 0 {
+        for entry in (*table(frame)).iter() {
+            // huge pages are never mapped in the user half
+            if let Ok(child) = entry.frame() {
+                free_tree(child, level - 1, frame_deallocator);
+            }
+        }
+    }
+    frame_deallocator.deallocate_frame(frame);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropped the active address space");
+        vma::remove_user(self.p4);
+        let p4 = unsafe { &*table(self.p4) };
+        for (index, entry) in p4.iter().enumerate() {
+            if let (true, Ok(p3)) = (is_user_entry(index), entry.frame()) {
+                unsafe { free_tree(p3, 3, &mut GlobalFrameAllocator) };
+            }
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.p4) };
+    }
+}
+
+#[test_case]
+fn test_separate_user_mappings() {
+    use super::with_memory;
+    use PageTableFlags as Flags;
+
+    let page = Page::containing_address(VirtAddr::new(USER_SPACE_START + 0x1000));
+    let (a, b) = with_memory(|memory| {
+        let mut a = AddressSpace::new(&mut memory.frame_allocator).unwrap();
+        let b = AddressSpace::new(&mut memory.frame_allocator).unwrap();
+        a.map(
+            Page::range_inclusive(page, page),
+            Flags::PRESENT | Flags::WRITABLE,
+            &mut memory.frame_allocator,
+        )
+        .unwrap();
+        (a, b)
+    });
+
+    assert!(a.translate(page.start_address()).is_some());
+    assert!(b.translate(page.start_address()).is_none());
+    // kernel mappings are shared
+    let kernel_addr = VirtAddr::from_ptr(&page);
+    assert_eq!(a.translate(kernel_addr), b.translate(kernel_addr));
+    assert!(a.translate(kernel_addr).is_some());
+}
+
+#[test_case]
+fn test_drop_frees_frames() {
+    use super::{frame_stats, with_memory};
+    use PageTableFlags as Flags;
+
+    let before = frame_stats().free_frames;
+    let start = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    with_memory(|memory| {
+        let mut space = AddressSpace::new(&mut memory.frame_allocator).unwrap();
+        space
+            .map(
+                Page::range_inclusive(start, start + 15),
+                Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE,
+                &mut memory.frame_allocator,
+            )
+            .unwrap();
+        space
+            .unmap(
+                Page::range_inclusive(start, start + 3),
+                &mut memory.frame_allocator,
+            )
+            .unwrap();
+    });
+    assert_eq!(frame_stats().free_frames, before);
+}
+
+#[test_case]
+fn test_lazy_areas() {
+    use super::with_memory;
+    use PageTableFlags as Flags;
+
+    let start = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    with_memory(|memory| {
+        let mut space = AddressSpace::new(&mut memory.frame_allocator).unwrap();
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        space
+            .map(
+                Page::range_inclusive(start, start),
+                flags,
+                &mut memory.frame_allocator,
+            )
+            .unwrap();
+        let lazy = Page::range_inclusive(start + 1, start + 8);
+        assert_eq!(
+            space.map_lazy(Page::range_inclusive(start, start + 1), flags, "test"),
+            Err(VmaError::Overlap)
+        );
+        assert_eq!(space.map_lazy(lazy, flags, "test"), Ok(()));
+        assert_eq!(
+            space.map_lazy(Page::range_inclusive(start + 8, start + 9), flags, "test"),
+            Err(VmaError::Overlap)
+        );
+        // lazy pages aren't mapped until they're accessed
+        assert!(space.translate((start + 1).start_address()).is_none());
+    });
+}
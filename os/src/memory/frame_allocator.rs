@@ -0,0 +1,314 @@
+use super::phys_to_virt;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// Largest block handed out at once: 2^10 frames, 4 MiB.
+pub const MAX_ORDER: usize = 10;
+
+const FREE: u8 = 0x80;
+const NONE: u64 = u64::MAX;
+
+/// Links of a free list, stored in the first frame of each free block.
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+fn block(frame: u64) -> *mut FreeBlock {
+    phys_to_virt(PhysAddr::new(frame * 4096)).as_mut_ptr()
+}
+
+/// Binary buddy allocator for physical frames.
+///
+/// Free blocks of 2^order frames are kept in one doubly linked list per
+/// order, threaded through the free frames themselves. A byte per frame
+/// records which frames start a free block and of which order, so freeing
+/// finds and merges the buddy in O(1) per order.
+pub struct BuddyFrameAllocator {
+    /// `FREE | order` for the first frame of a free block, 0 for all others.
+    state: &'static mut [u8],
+    heads: [u64; MAX_ORDER + 1],
+    blocks: [usize; MAX_ORDER + 1],
+    total: usize,
+    free: usize,
+}
+
+/// Frame counts, as returned by `frame_stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// Number of free blocks of each order.
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
+
+impl FrameStats {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Size of the largest contiguous allocation that can currently succeed.
+    pub fn largest_free_block(&self) -> usize {
+        self.free_blocks
+            .iter()
+            .rposition(|&count| count > 0)
+            .map_or(0, |order| 1 << order)
+    }
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator owning all usable frames of the memory map.
+    ///
+    /// Unsafe because the caller must guarantee that the usable frames are
+    /// really unused and that the physical memory offset is already known.
+    /// Must be called only once.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| (r.range.start_addr() / 4096, r.range.end_addr() / 4096))
+        };
+        let frame_count = usable().map(|(_, end)| end).max().unwrap_or(0) as usize;
+
+        // the state array lives at the start of the first region large enough
+        let state_frames = ((frame_count + 4095) / 4096) as u64;
+        let (state_start, _) = usable()
+            .find(|&(start, end)| start > 0 && end - start > state_frames)
+            .expect("no memory for the frame allocator");
+        let state_ptr = phys_to_virt(PhysAddr::new(state_start * 4096)).as_mut_ptr();
+        let state = core::slice::from_raw_parts_mut(state_ptr, frame_count);
+        state.fill(0);
+
+        let mut allocator = BuddyFrameAllocator {
+            state,
+            heads: [NONE; MAX_ORDER + 1],
+            blocks: [0; MAX_ORDER + 1],
+            total: 0,
+            free: 0,
+        };
+        for (start, end) in usable() {
+            let mut start = start.max(1); // never hand out the null frame
+            if start == state_start {
+                start += state_frames;
+            }
+            for frame in start..end {
+                allocator.release(frame, 0);
+            }
+            allocator.total += end.saturating_sub(start) as usize;
+        }
+        allocator.free = allocator.total;
+        allocator
+    }
+
+    fn push(&mut self, frame: u64, order: usize) {
+        let head = self.heads[order];
+        unsafe {
+            block(frame).write(FreeBlock {
+                next: head,
+                prev: NONE,
+            });
+            if head != NONE {
+                (*block(head)).prev = frame;
+            }
+        }
+        self.heads[order] = frame;
+        self.blocks[order] += 1;
+        self.state[frame as usize] = FREE | order as u8;
+    }
+
+    fn remove(&mut self, frame: u64, order: usize) {
+        let FreeBlock { next, prev } = unsafe { block(frame).read() };
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            unsafe { (*block(prev)).next = next };
+        }
+        if next != NONE {
+            unsafe { (*block(next)).prev = prev };
+        }
+        self.blocks[order] -= 1;
+        self.state[frame as usize] = 0;
+    }
+
+    /// Adds the block to the free lists, merging it with its buddy for as
+    /// long as that is free too.
+    fn release(&mut self, mut frame: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            match self.state.get(buddy as usize) {
+                Some(&state) if state == FREE | order as u8 => {}
+                _ => break,
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Allocates a block of 2^order frames, aligned to its size.
+    fn allocate(&mut self, order: usize) -> Option<u64> {
+        let found = (order..=MAX_ORDER).find(|&k| self.heads[k] != NONE)?;
+        let frame = self.heads[found];
+        self.remove(frame, found);
+        // give back the upper halves we don't need
+        for k in (order..found).rev() {
+            self.push(frame + (1 << k), k);
+        }
+        self.free -= 1 << order;
+        Some(frame)
+    }
+
+    /// Allocates `count` physically contiguous frames, aligned to the next
+    /// power of two.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+        let start = self.allocate(order)?;
+        for frame in start + count as u64..start + (1 << order) {
+            self.deallocate(frame);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(start * 4096)))
+    }
+
+    /// Frees frames allocated by `allocate_contiguous`.
+    ///
+    /// Unsafe because the frames must not be in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let start = start.start_address().as_u64() / 4096;
+        for frame in start..start + count as u64 {
+            self.deallocate(frame);
+        }
+    }
+
+    fn deallocate(&mut self, frame: u64) {
+        assert!(
+            self.state[frame as usize] & FREE == 0,
+            "frame {:#x} freed twice",
+            frame * 4096
+        );
+        self.free += 1;
+        self.release(frame, 0);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total,
+            free_frames: self.free,
+            free_blocks: self.blocks,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.allocate(0)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(frame * 4096)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame.start_address().as_u64() / 4096);
+    }
+}
+
+/// The kernel's frame allocator. Only locked with interrupts disabled, so
+/// page fault handlers may use it too.
+static FRAMES: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+/// Hands all usable frames of `memory_map` to the global frame allocator.
+///
+/// Unsafe for the same reasons as `BuddyFrameAllocator::init`.
+pub unsafe fn init(memory_map: &'static MemoryMap) {
+    let allocator = BuddyFrameAllocator::init(memory_map);
+    interrupts::without_interrupts(|| *FRAMES.lock() = Some(allocator));
+}
+
+fn with_frames<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        f(FRAMES
+            .lock()
+            .as_mut()
+            .expect("frame allocator not initialized"))
+    })
+}
+
+/// Handle to the global frame allocator, for APIs that take a
+/// `FrameAllocator` or `FrameDeallocator`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frames(|frames| frames.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        with_frames(|frames| frames.deallocate_frame(frame))
+    }
+}
+
+/// Allocates a frame unless the allocator is locked, for the page fault
+/// handler: the faulting code may hold the lock.
+pub(crate) fn try_allocate_frame() -> Option<PhysFrame> {
+    interrupts::without_interrupts(|| FRAMES.try_lock()?.as_mut()?.allocate_frame())
+}
+
+/// See `BuddyFrameAllocator::allocate_contiguous`.
+pub fn allocate_contiguous(count: usize) -> Option<PhysFrame> {
+    with_frames(|frames| frames.allocate_contiguous(count))
+}
+
+/// See `BuddyFrameAllocator::deallocate_contiguous`.
+pub unsafe fn deallocate_contiguous(start: PhysFrame, count: usize) {
+    with_frames(|frames| frames.deallocate_contiguous(start, count))
+}
+
+pub fn frame_stats() -> FrameStats {
+    with_frames(|frames| frames.stats())
+}
+
+#[test_case]
+fn test_allocate_and_free() {
+    let before = frame_stats();
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_eq!(frame_stats().free_frames, before.free_frames - 1);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    assert_eq!(frame_stats().free_frames, before.free_frames);
+}
+
+#[test_case]
+fn test_contiguous_blocks_are_aligned() {
+    let before = frame_stats();
+    let start = allocate_contiguous(5).unwrap();
+    assert_eq!(start.start_address().as_u64() % (8 * 4096), 0);
+    assert_eq!(frame_stats().free_frames, before.free_frames - 5);
+    unsafe { deallocate_contiguous(start, 5) };
+    assert_eq!(frame_stats().free_frames, before.free_frames);
+}
+
+#[test_case]
+fn test_buddies_merge() {
+    let before = frame_stats();
+    let frames = [(); 64].map(|_| GlobalFrameAllocator.allocate_frame().unwrap());
+    for frame in frames {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+    // every split block was merged back
+    assert_eq!(frame_stats().free_blocks, before.free_blocks);
+}
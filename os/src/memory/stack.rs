@@ -0,0 +1,168 @@
+//! Kernel stacks with guard pages.
+//!
+//! Stacks live in a region of the kernel half reserved for them, one per
+//! slot of `SLOT_SIZE` bytes. A stack occupies the top of its slot and the
+//! rest, at least one page, is never mapped, so running off the end of a
+//! stack faults instead of silently overwriting the memory below it.
+//!
+//! The region's level 4 entry is created by the first stack, which `gdt`
+//! allocates during boot, before any address space copies the kernel half.
+
+use super::{with_memory, GlobalFrameAllocator};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+pub const STACKS_START: u64 = 0xFFFF_A000_0000_0000;
+const SLOT_SIZE: u64 = 64 * 1024;
+/// Largest stack that leaves room for a guard page in its slot.
+pub const MAX_STACK_SIZE: usize = SLOT_SIZE as usize - 4096;
+const MAX_STACKS: usize = 64;
+const STACKS_END: u64 = STACKS_START + MAX_STACKS as u64 * SLOT_SIZE;
+
+/// The bootloader leaves the lowest page of the boot stack, at
+/// `kernel-stack-address` in `Cargo.toml`, unmapped.
+const BOOT_STACK_GUARD: u64 = 0xFFFF_FF00_0000_0000;
+
+/// Bit `i` is set while slot `i` is in use.
+static SLOTS: AtomicU64 = AtomicU64::new(0);
+
+/// A mapped kernel stack, unmapped and freed when dropped.
+pub struct KernelStack {
+    slot: usize,
+    size: usize,
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    TooLarge,
+    TooManyStacks,
+    OutOfMemory,
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        StackError::OutOfMemory
+    }
+}
+
+impl KernelStack {
+    /// Maps a stack of at least `size` bytes.
+    pub fn new(size: usize) -> Result<Self, StackError> {
+        let size = (size + 4095) & !4095;
+        if size > MAX_STACK_SIZE {
+            return Err(StackError::TooLarge);
+        }
+        let slot = take_slot().ok_or(StackError::TooManyStacks)?;
+        let stack = KernelStack { slot, size };
+        // on failure, dropping `stack` unmaps what was mapped
+        with_memory(|memory| -> Result<(), StackError> {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            for page in stack.pages() {
+                let frame = memory
+                    .frame_allocator
+                    .allocate_frame()
+                    .ok_or(StackError::OutOfMemory)?;
+                let mapped = unsafe {
+                    memory
+                        .mapper
+                        .map_to(page, frame, flags, &mut memory.frame_allocator)
+                };
+                match mapped {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                        return Err(err.into());
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(stack)
+    }
+
+    fn slot_start(&self) -> u64 {
+        STACKS_START + self.slot as u64 * SLOT_SIZE
+    }
+
+    /// End of the stack, 16-byte aligned.
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(self.slot_start() + SLOT_SIZE)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.size
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        Page::range(
+            Page::containing_address(self.bottom()),
+            Page::containing_address(self.top()),
+        )
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        with_memory(|memory| {
+            for page in self.pages() {
+                // pages after a failed mapping aren't mapped
+                if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+            }
+        });
+        SLOTS.fetch_and(!(1 << self.slot), Ordering::AcqRel);
+    }
+}
+
+fn take_slot() -> Option<usize> {
+    let mut slots = SLOTS.load(Ordering::Acquire);
+    loop {
+        let slot = (!slots).trailing_zeros() as usize;
+        if slot >= MAX_STACKS {
+            return None;
+        }
+        match SLOTS.compare_exchange_weak(
+            slots,
+            slots | 1 << slot,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return Some(slot),
+            Err(current) => slots = current,
+        }
+    }
+}
+
+/// Returns `true` if `addr` lies below the end of a kernel stack, where
+/// only a stack overflow can fault. Doesn't lock, for fault handlers.
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64();
+    (STACKS_START..STACKS_END).contains(&addr)
+        || (BOOT_STACK_GUARD..BOOT_STACK_GUARD + 4096).contains(&addr)
+}
+
+#[test_case]
+fn test_stack_is_mapped_and_freed() {
+    let frames = super::frame_stats().free_frames;
+    let stack = KernelStack::new(8192).unwrap();
+    let top = stack.top().as_u64();
+    assert_eq!(top % 16, 0);
+    unsafe { ((top - 8) as *mut u64).write(42) };
+    unsafe { (stack.bottom().as_u64() as *mut u64).write(42) };
+    assert!(is_guard_page(stack.bottom() - 1u64));
+    assert!(super::frame_stats().free_frames < frames);
+    drop(stack);
+    assert_eq!(super::frame_stats().free_frames, frames);
+}
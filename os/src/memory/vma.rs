@@ -0,0 +1,340 @@
+//! Virtual memory areas mapped on demand.
+//!
+//! A lazy area is registered with the flags its pages get, but none of its
+//! pages is mapped until it's first accessed: the page fault handler then
+//! maps a fresh, zeroed frame and resumes the faulting code.
+//!
+//! Areas belong either to an `AddressSpace`, identified by its level 4
+//! frame, or to the kernel. Kernel areas are `KernelArea`s, which live in a
+//! region of the kernel half whose page tables only this module touches, so
+//! faults never race with `with_memory` users. The region's level 4 entry is
+//! created by `init`, before any address space copies the kernel half.
+//!
+//! The registry is locked with interrupts disabled, since the page fault
+//! handler looks areas up.
+
+use super::{
+    phys_to_virt, physical_memory_offset, with_memory, GlobalFrameAllocator, USER_SPACE_END,
+    USER_SPACE_START,
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+            Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        },
+    },
+    VirtAddr,
+};
+
+/// Start of the region for `KernelArea`s, covered by one level 4 entry.
+pub const LAZY_START: u64 = 0xFFFF_B000_0000_0000;
+pub const LAZY_END: u64 = LAZY_START + (1 << 39);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The area lies outside the region it must be in.
+    BadRange,
+    /// The area overlaps another one or mapped pages.
+    Overlap,
+    /// There's no room for a kernel area of the requested size.
+    NoSpace,
+}
+
+/// A lazily mapped range of pages.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub pages: PageRangeInclusive,
+    pub flags: PageTableFlags,
+    pub name: &'static str,
+    /// Level 4 frame of the owning address space, `None` for the kernel.
+    owner: Option<PhysFrame>,
+}
+
+impl Vma {
+    fn contains(&self, page: Page) -> bool {
+        self.pages.start <= page && page <= self.pages.end
+    }
+
+    fn overlaps(&self, pages: PageRangeInclusive) -> bool {
+        self.pages.start <= pages.end && pages.start <= self.pages.end
+    }
+
+    /// Returns `true` if the pages of the area allow the access described by
+    /// `error`.
+    fn allows(&self, error: PageFaultErrorCode) -> bool {
+        use PageTableFlags as Flags;
+
+        (!error.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            || self.flags.contains(Flags::WRITABLE))
+            && (!error.contains(PageFaultErrorCode::USER_MODE)
+                || self.flags.contains(Flags::USER_ACCESSIBLE))
+            && (!error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                || !self.flags.contains(Flags::NO_EXECUTE))
+    }
+}
+
+static VMAS: Mutex<Vec<Vma>> = Mutex::new(Vec::new());
+static DEMAND_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+/// Creates the level 4 entry of the `KernelArea` region.
+pub fn init() {
+    use PageTableFlags as Flags;
+
+    with_memory(|memory| {
+        let frame = memory
+            .frame_allocator
+            .allocate_frame()
+            .expect("no frame for the lazy region's page table");
+        let table: *mut PageTable = phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { table.write(PageTable::new()) };
+        let entry = &mut memory.mapper.level_4_table()[VirtAddr::new(LAZY_START).p4_index()];
+        assert!(entry.is_unused(), "lazy region already in use");
+        entry.set_frame(frame, Flags::PRESENT | Flags::WRITABLE);
+    });
+}
+
+/// Number of pages mapped on demand so far.
+pub fn demand_faults() -> u64 {
+    DEMAND_FAULTS.load(Ordering::Relaxed)
+}
+
+/// Registers `pages` as a lazy area of the address space with level 4 table
+/// `owner`. Used by `AddressSpace::map_lazy`, which checks the range.
+pub(super) fn register_user(
+    owner: PhysFrame,
+    pages: PageRangeInclusive,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<(), VmaError> {
+    let vma = Vma {
+        pages,
+        flags,
+        name,
+        owner: Some(owner),
+    };
+    interrupts::without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        let overlaps = vmas
+            .iter()
+            .any(|other| other.owner == vma.owner && other.overlaps(pages));
+        if overlaps {
+            return Err(VmaError::Overlap);
+        }
+        vmas.push(vma);
+        Ok(())
+    })
+}
+
+/// Forgets all areas of the address space with level 4 table `owner`. Their
+/// frames are freed along with the address space.
+pub(super) fn remove_user(owner: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        VMAS.lock().retain(|vma| vma.owner != Some(owner));
+    });
+}
+
+/// Maps a zeroed frame at `addr` if it lies in a lazy area of the active
+/// address space or the kernel that allows the access. Returns `false` if
+/// the fault wasn't caused by a lazy area, or no frame was left.
+///
+/// Called by the page fault handler; doesn't allocate or wait for locks.
+pub(crate) fn handle_fault(addr: VirtAddr, error: PageFaultErrorCode) -> bool {
+    // present pages were mapped already, the access itself isn't allowed
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let (p4, _) = Cr3::read();
+    let page = Page::containing_address(addr);
+    // a kernel bug may fault with the lock held, which must reach the crash
+    // screen instead of spinning
+    let vma = interrupts::without_interrupts(|| {
+        let vmas = VMAS.try_lock()?;
+        vmas.iter()
+            .find(|vma| vma.contains(page) && (vma.owner.is_none() || vma.owner == Some(p4)))
+            .copied()
+    });
+    match vma {
+        Some(vma) if vma.allows(error) => map_zeroed(p4, page, vma.flags),
+        _ => false,
+    }
+}
+
+/// Maps the not yet accessed lazy pages of the active address space in
+/// `start..start + len`, so the kernel can access them on behalf of the
+/// process without faulting. Pages outside lazy areas are left alone.
+pub(crate) fn populate(start: VirtAddr, len: u64, write: bool) {
+    let end = match start.as_u64().checked_add(len) {
+        Some(end) if len > 0 && start.as_u64() >= USER_SPACE_START && end <= USER_SPACE_END => end,
+        _ => return,
+    };
+    let mut error = PageFaultErrorCode::USER_MODE;
+    if write {
+        error |= PageFaultErrorCode::CAUSED_BY_WRITE;
+    }
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        if !super::is_mapped(page.start_address()) {
+            handle_fault(page.start_address(), error);
+        }
+    }
+}
+
+fn map_zeroed(p4: PhysFrame, page: Page, flags: PageTableFlags) -> bool {
+    // if the frame allocator is free now, nothing can take it before the
+    // handler returns, since interrupts are disabled
+    let frame = match super::frame_allocator::try_allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            4096,
+        );
+        let mut mapper = mapper(p4);
+        match mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) {
+            Ok(flush) => {
+                flush.flush();
+                DEMAND_FAULTS.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => {
+                GlobalFrameAllocator.deallocate_frame(frame);
+                false
+            }
+        }
+    }
+}
+
+/// Unsafe because nothing else may change the tables `mapper` is used on.
+unsafe fn mapper(p4: PhysFrame) -> OffsetPageTable<'static> {
+    let table: *mut PageTable = phys_to_virt(p4.start_address()).as_mut_ptr();
+    OffsetPageTable::new(&mut *table, physical_memory_offset())
+}
+
+/// A lazy area in the kernel half, unmapped and freed when dropped.
+pub struct KernelArea {
+    pages: PageRangeInclusive,
+}
+
+impl KernelArea {
+    /// Reserves an area of at least `size` bytes with `flags`, which must
+    /// include `PRESENT`.
+    pub fn new(size: usize, flags: PageTableFlags, name: &'static str) -> Result<Self, VmaError> {
+        let size = (size as u64 + 4095) & !4095;
+        if size == 0 || size > LAZY_END - LAZY_START {
+            return Err(VmaError::BadRange);
+        }
+        interrupts::without_interrupts(|| {
+            let mut vmas = VMAS.lock();
+            // first fit between the kernel areas, which are kept sorted
+            let mut start = LAZY_START;
+            let mut index = 0;
+            for (i, vma) in vmas.iter().enumerate() {
+                if vma.owner.is_some() {
+                    continue;
+                }
+                if vma.pages.start.start_address().as_u64() >= start + size {
+                    break;
+                }
+                start = vma.pages.end.start_address().as_u64() + 4096;
+                index = i + 1;
+            }
+            if size > LAZY_END - start {
+                return Err(VmaError::NoSpace);
+            }
+            let pages = Page::range_inclusive(
+                Page::containing_address(VirtAddr::new(start)),
+                Page::containing_address(VirtAddr::new(start + size - 1)),
+            );
+            vmas.insert(
+                index,
+                Vma {
+                    pages,
+                    flags,
+                    name,
+                    owner: None,
+                },
+            );
+            Ok(KernelArea { pages })
+        })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.pages.start.start_address()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages.end.start_address() - self.start() + 4096
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start().as_mut_ptr()
+    }
+}
+
+impl Drop for KernelArea {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            VMAS.lock()
+                .retain(|vma| vma.owner.is_some() || vma.pages != self.pages);
+            let mut mapper = unsafe { mapper(super::kernel_page_table()) };
+            for page in self.pages {
+                // pages that were never accessed aren't mapped
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_kernel_area_maps_on_demand() {
+    use super::frame_stats;
+    use PageTableFlags as Flags;
+
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+    // the first area may leave page tables behind
+    drop(KernelArea::new(4096, flags, "test").unwrap());
+    let frames = frame_stats().free_frames;
+    let area = KernelArea::new(64 * 1024, flags, "test").unwrap();
+    let faults = demand_faults();
+    assert!(!super::is_mapped(area.start()));
+    let words = area.as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(words.add(1000).read_volatile(), 0);
+        words.add(1000).write_volatile(42);
+        assert_eq!(words.add(1000).read_volatile(), 42);
+    }
+    assert_eq!(demand_faults(), faults + 1);
+    assert!(super::is_mapped(area.start() + 8000u64));
+    assert!(!super::is_mapped(area.start()));
+    drop(area);
+    assert_eq!(frame_stats().free_frames, frames);
+}
+
+#[test_case]
+fn test_kernel_areas_dont_overlap() {
+    use PageTableFlags as Flags;
+
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let a = KernelArea::new(8192, flags, "a").unwrap();
+    let b = KernelArea::new(4096, flags, "b").unwrap();
+    assert!(b.start() >= a.start() + a.size() || a.start() >= b.start() + b.size());
+    let start = a.start();
+    drop(a);
+    let c = KernelArea::new(8192, flags, "c").unwrap();
+    assert_eq!(c.start(), start);
+    drop((b, c));
+}
@@ -0,0 +1,530 @@
+//! User processes running in ring 3.
+//!
+//! Programs are ELF executables, usually from the `initrd`. Each process has
+//! an `AddressSpace` of its own: position independent executables are loaded
+//! at `USER_BASE`, others wherever they were linked to in user space. The user
+//! stack ends at `USER_STACK_TOP`.
+//!
+//! Each process runs on its own kernel thread, which `iretq`s to the entry
+//! point once the scheduler switched to the process's page table.
+//!
+//! The stack and the zero-filled ends of segments are lazy areas, mapped as
+//! the process touches them. A process raising any other exception in user
+//! mode is killed with `EXIT_FAULT`.
+//!
+//! Processes talk to the kernel through the system calls in `syscall`.
+
+use crate::{
+    elf::{ElfError, ElfFile, Segment},
+    exceptions::ExceptionContext,
+    gdt, initrd,
+    memory::{self, vma::VmaError, AddressSpace, USER_SPACE_START},
+    task::sync::Notify,
+    thread::{self, SavedContext},
+};
+use alloc::{string::String, sync::Arc};
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Page,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+pub const MAX_PROCESSES: usize = 16;
+/// Load address of position independent executables.
+pub const USER_BASE: u64 = 0x0000_6000_0000_0000;
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const USER_STACK_SIZE: u64 = 4096 * 4;
+/// Lowest address of the user stack, which has an unmapped guard page below.
+const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_SIZE;
+
+const KEY_QUEUE_SIZE: usize = 64;
+
+/// Exit code of processes killed for an exception, like a shell reports a
+/// process killed by `SIGSEGV`.
+pub const EXIT_FAULT: u64 = 139;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    UnknownProgram,
+    TooManyProcesses,
+    /// All `thread::MAX_THREADS` threads are in use.
+    TooManyThreads,
+    OutOfMemory,
+    InvalidElf(ElfError),
+    /// A segment or the entry point lies outside user space or overlaps the
+    /// stack. Overlapping segments are not rejected, since segments may
+    /// share the pages at their boundaries.
+    BadSegment,
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(err: ElfError) -> Self {
+        SpawnError::InvalidElf(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for SpawnError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        SpawnError::OutOfMemory
+    }
+}
+
+impl From<VmaError> for SpawnError {
+    fn from(_: VmaError) -> Self {
+        SpawnError::BadSegment
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    slot: usize,
+    /// Dropped once the process exits.
+    address_space: Mutex<Option<AddressSpace>>,
+    exited: AtomicBool,
+    exit_code: AtomicU64,
+    exit: Notify,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// `None` while the process is still running.
+    pub fn exit_code(&self) -> Option<u64> {
+        if self.exited.load(Ordering::Acquire) {
+            Some(self.exit_code.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+
+    /// Waits for the process to exit and returns its exit code.
+    pub async fn wait(&self) -> u64 {
+        loop {
+            let exit = self.exit.notified();
+            if let Some(code) = self.exit_code() {
+                return code;
+            }
+            exit.await;
+        }
+    }
+
+    /// Records the exit code and releases everything but the thread, which
+    /// exits right after. Runs on that thread.
+    fn finish(&self, code: u64) {
+        thread::leave_address_space();
+        drop(self.address_space.lock().take());
+        let _ = FOREGROUND.compare_exchange(self.pid.0, 0, Ordering::Relaxed, Ordering::Relaxed);
+        PROCESSES.lock()[self.slot] = None;
+
+        self.exit_code.store(code, Ordering::Relaxed);
+        self.exited.store(true, Ordering::Release);
+        self.exit.notify_waiters();
+    }
+}
+
+/// Running processes. Only used from thread context.
+static PROCESSES: Mutex<[Option<Arc<Process>>; MAX_PROCESSES]> = {
+    const EMPTY: Option<Arc<Process>> = None;
+    Mutex::new([EMPTY; MAX_PROCESSES])
+};
+
+/// Starts a process running the ELF executable `image`.
+pub fn spawn(name: &str, image: &[u8]) -> Result<Arc<Process>, SpawnError> {
+    let elf = ElfFile::parse(image)?;
+    let (address_space, entry) = memory::with_memory(|memory| {
+        let mut address_space =
+            AddressSpace::new(&mut memory.frame_allocator).ok_or(SpawnError::OutOfMemory)?;
+        let entry = load(&mut address_space, &elf, &mut memory.frame_allocator)?;
+        Ok::<_, SpawnError>((address_space, entry))
+    })?;
+    let page_table = address_space.p4_frame();
+
+    let process = {
+        let mut processes = PROCESSES.lock();
+        let slot = processes
+            .iter()
+            .position(Option::is_none)
+            .ok_or(SpawnError::TooManyProcesses)?;
+        let process = Arc::new(Process {
+            pid: Pid::new(),
+            name: String::from(name),
+            slot,
+            address_space: Mutex::new(Some(address_space)),
+            exited: AtomicBool::new(false),
+            exit_code: AtomicU64::new(0),
+            exit: Notify::new(),
+        });
+        processes[slot] = Some(Arc::clone(&process));
+        process
+    };
+
+    let thread = thread::spawn_process(Arc::clone(&process), page_table, move || unsafe {
+        enter_user_mode(entry.as_u64(), USER_STACK_TOP)
+    });
+    if thread.is_none() {
+        // never ran, so dropping it frees the address space too
+        PROCESSES.lock()[process.slot] = None;
+        return Err(SpawnError::TooManyThreads);
+    }
+    Ok(process)
+}
+
+/// Starts the program called `name` from the initrd.
+pub fn exec(name: &str) -> Result<Arc<Process>, SpawnError> {
+    let image = initrd::get(name).ok_or(SpawnError::UnknownProgram)?;
+    spawn(name, image)
+}
+
+fn stack_pages() -> PageRangeInclusive {
+    Page::range_inclusive(
+        Page::containing_address(VirtAddr::new(USER_STACK_BOTTOM)),
+        Page::containing_address(VirtAddr::new(USER_STACK_TOP - 1)),
+    )
+}
+
+/// Maps the segments of `elf` and the user stack into `address_space` and
+/// returns the entry point.
+///
+/// The pages holding file data are mapped right away, the rest of each
+/// segment and the stack become lazy areas. Segments may share the pages at
+/// their boundaries.
+fn load(
+    address_space: &mut AddressSpace,
+    elf: &ElfFile,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<VirtAddr, SpawnError> {
+    use PageTableFlags as Flags;
+
+    let bias = if elf.is_position_independent() {
+        USER_BASE
+    } else {
+        0
+    };
+    // keep an unmapped guard page between the program and the stack
+    let (lowest, limit) = (USER_SPACE_START, USER_STACK_BOTTOM - 4096);
+    let range = |segment: &Segment| -> Result<(VirtAddr, VirtAddr), SpawnError> {
+        let start = segment
+            .vaddr
+            .checked_add(bias)
+            .ok_or(SpawnError::BadSegment)?;
+        if start < lowest || start >= limit || segment.mem_size > limit - start {
+            return Err(SpawnError::BadSegment);
+        }
+        let start = VirtAddr::new(start);
+        Ok((start, start + segment.mem_size))
+    };
+
+    // all data first, so the lazy areas can leave out pages holding data
+    for segment in elf.segments() {
+        let segment = segment?;
+        if segment.data.is_empty() {
+            continue;
+        }
+        let (start, _) = range(&segment)?;
+        let data_end = start + segment.data.len();
+        let pages = Page::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(data_end - 1u64),
+        );
+        for page in pages {
+            map_user_page(
+                address_space,
+                page,
+                segment_flags(&segment),
+                start,
+                segment.data,
+                frame_allocator,
+            )?;
+        }
+    }
+
+    for segment in elf.segments() {
+        let segment = segment?;
+        if segment.mem_size == 0 {
+            continue;
+        }
+        let (start, end) = range(&segment)?;
+        let lazy_start = if segment.data.is_empty() {
+            start.align_down(4096u64)
+        } else {
+            (start + segment.data.len()).align_up(4096u64)
+        };
+        if lazy_start < end {
+            let pages = Page::range_inclusive(
+                Page::containing_address(lazy_start),
+                Page::containing_address(end - 1u64),
+            );
+            map_lazy_around(address_space, pages, segment_flags(&segment))?;
+        }
+    }
+
+    let stack_flags = Flags::PRESENT | Flags::USER_ACCESSIBLE | Flags::WRITABLE | Flags::NO_EXECUTE;
+    address_space.map_lazy(stack_pages(), stack_flags, "stack")?;
+
+    match elf.entry().checked_add(bias) {
+        Some(entry) if entry >= lowest && entry < limit => Ok(VirtAddr::new(entry)),
+        _ => Err(SpawnError::BadSegment),
+    }
+}
+
+fn segment_flags(segment: &Segment) -> PageTableFlags {
+    use PageTableFlags as Flags;
+
+    let mut flags = Flags::PRESENT | Flags::USER_ACCESSIBLE;
+    if segment.writable {
+        flags |= Flags::WRITABLE;
+    }
+    if !segment.executable {
+        flags |= Flags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Maps `page` to a fresh frame holding the part of `data`, loaded at
+/// `start`, that falls into it. The rest of the frame is zeroed.
+///
+/// Segments may share the page at their boundary: if `page` is mapped
+/// already, the data is copied into its frame and the page gets the flags
+/// of both segments.
+fn map_user_page(
+    address_space: &mut AddressSpace,
+    page: Page,
+    flags: PageTableFlags,
+    start: VirtAddr,
+    data: &[u8],
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), SpawnError> {
+    if let Some((frame, old_flags)) = address_space.lookup(page) {
+        copy_to_frame(frame, page, start, data);
+        share_page(address_space, page, old_flags, flags)?;
+        return Ok(());
+    }
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(SpawnError::OutOfMemory)?;
+    unsafe {
+        core::ptr::write_bytes(
+            memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            4096,
+        );
+    }
+    copy_to_frame(frame, page, start, data);
+    unsafe {
+        if let Err(err) = address_space.map_to(page, frame, flags, frame_allocator) {
+            frame_allocator.deallocate_frame(frame);
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+/// Copies the part of `data`, loaded at `start`, that falls into `page` to
+/// `frame`. The address space isn't active, so the frame is written through
+/// the physical memory mapping.
+fn copy_to_frame(frame: PhysFrame, page: Page, start: VirtAddr, data: &[u8]) {
+    let dest = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    let page_start = page.start_address().as_u64();
+    let data_start = start.as_u64();
+    let data_end = data_start + data.len() as u64;
+    let from = page_start.max(data_start);
+    let to = (page_start + 4096).min(data_end);
+    if from < to {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data[(from - data_start) as usize..].as_ptr(),
+                dest.add((from - page_start) as usize),
+                (to - from) as usize,
+            );
+        }
+    }
+}
+
+/// Gives a page shared by two segments the permissions of both.
+fn share_page(
+    address_space: &mut AddressSpace,
+    page: Page,
+    old_flags: PageTableFlags,
+    flags: PageTableFlags,
+) -> Result<(), SpawnError> {
+    use PageTableFlags as Flags;
+
+    let mut merged = (old_flags | flags) - Flags::NO_EXECUTE;
+    if old_flags.contains(Flags::NO_EXECUTE) && flags.contains(Flags::NO_EXECUTE) {
+        merged |= Flags::NO_EXECUTE;
+    }
+    if merged != old_flags {
+        address_space
+            .protect(Page::range_inclusive(page, page), merged)
+            .map_err(|_| SpawnError::BadSegment)?;
+    }
+    Ok(())
+}
+
+/// Registers the pages in `pages` that aren't mapped yet as lazy areas. The
+/// mapped ones hold data of another segment and get `flags` added.
+fn map_lazy_around(
+    address_space: &mut AddressSpace,
+    pages: PageRangeInclusive,
+    flags: PageTableFlags,
+) -> Result<(), SpawnError> {
+    let mut run: Option<Page> = None;
+    for page in pages {
+        match address_space.lookup(page) {
+            Some((_, old_flags)) => {
+                if let Some(first) = run.take() {
+                    let lazy = Page::range_inclusive(first, page - 1);
+                    address_space.map_lazy(lazy, flags, "zero-filled segment")?;
+                }
+                share_page(address_space, page, old_flags, flags)?;
+            }
+            None => {
+                run.get_or_insert(page);
+            }
+        }
+    }
+    if let Some(first) = run {
+        let lazy = Page::range_inclusive(first, pages.end);
+        address_space.map_lazy(lazy, flags, "zero-filled segment")?;
+    }
+    Ok(())
+}
+
+/// Drops to ring 3 at `entry` with the given stack pointer.
+unsafe fn enter_user_mode(entry: u64, stack_top: u64) -> ! {
+    let (code, data) = gdt::user_selectors();
+    asm!(
+        "push {ss}",
+        "push {rsp}",
+        "push 0x202", // interrupts enabled
+        "push {cs}",
+        "push {rip}",
+        "iretq",
+        ss = in(reg) u64::from(data.0),
+        rsp = in(reg) stack_top,
+        cs = in(reg) u64::from(code.0),
+        rip = in(reg) entry,
+        options(noreturn),
+    )
+}
+
+/// The frame the CPU pushes when interrupted, which ends both
+/// `SavedContext` and `ExceptionContext`.
+#[repr(C)]
+struct InterruptFrame {
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// Makes the `iretq` through `frame` continue in kernel mode, in `exited`.
+/// The caller passes the exit code in `rdi`.
+fn return_to_exited(frame: &mut InterruptFrame) {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    // the CPU pushed the frame at the top of the thread's stack, which is
+    // 16-byte aligned; emulate a `call` from there
+    let stack_top =
+        frame as *mut InterruptFrame as u64 + core::mem::size_of::<InterruptFrame>() as u64;
+    frame.rip = exited as usize as u64;
+    frame.cs = u64::from(kernel_code.0);
+    frame.ss = u64::from(kernel_data.0);
+    frame.rsp = stack_top - 8;
+    frame.rflags = 0x202;
+}
+
+/// Makes the interrupted process continue in kernel mode, in `exited`, once
+/// the syscall handler returns.
+pub(crate) fn exit_to_kernel(context: &mut SavedContext, code: u64) {
+    context.rdi = code;
+    return_to_exited(unsafe { &mut *(&mut context.rip as *mut u64 as *mut InterruptFrame) });
+}
+
+/// Makes the process that raised a fatal exception in user mode exit with
+/// `EXIT_FAULT` once the exception handler returns.
+pub(crate) fn kill(context: &mut ExceptionContext) {
+    context.rdi = EXIT_FAULT;
+    return_to_exited(unsafe { &mut *(&mut context.rip as *mut u64 as *mut InterruptFrame) });
+}
+
+extern "C" fn exited(code: u64) -> ! {
+    if let Some(process) = thread::current_process() {
+        process.finish(code);
+    }
+    thread::exit();
+}
+
+/// Pid of the process keys are forwarded to, 0 for none.
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+static KEYS: OnceCell<ArrayQueue<char>> = OnceCell::uninit();
+
+/// Makes `process` receive the keys passed to `send_key`, dropping any keys
+/// the previous one didn't read.
+pub fn set_foreground(process: Option<&Process>) {
+    let keys = KEYS.get_or_init(|| ArrayQueue::new(KEY_QUEUE_SIZE));
+    FOREGROUND.store(
+        process.map_or(0, |process| process.pid.0),
+        Ordering::Relaxed,
+    );
+    while keys.pop().is_ok() {}
+}
+
+/// Returns `true` if a foreground process exists and the key was queued
+/// for it.
+pub fn send_key(key: char) -> bool {
+    if FOREGROUND.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    if let Ok(keys) = KEYS.try_get() {
+        // drop the key if the process isn't keeping up
+        let _ = keys.push(key);
+    }
+    true
+}
+
+/// Next key for the calling process, if it is in the foreground.
+pub(crate) fn next_key() -> Option<char> {
+    let pid = thread::current_process()?.pid;
+    if FOREGROUND.load(Ordering::Relaxed) != pid.0 {
+        return None;
+    }
+    KEYS.try_get().ok()?.pop().ok()
+}
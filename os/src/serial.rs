@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use uart_16550::SerialPort;
 
 lazy_static! {
@@ -10,6 +10,16 @@ lazy_static! {
     };
 }
 
+/// Locks `SERIAL1` even if it's held, for crash reports: whoever holds it
+/// was interrupted by the crash and won't run again.
+pub fn force_lock() -> MutexGuard<'static, SerialPort> {
+    if let Some(port) = SERIAL1.try_lock() {
+        return port;
+    }
+    unsafe { SERIAL1.force_unlock() };
+    SERIAL1.lock()
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -0,0 +1,88 @@
+//! System calls made by user processes through `int 0x80`.
+//!
+//! The syscall number is passed in `rax` and the arguments in `rdi` and `rsi`.
+//! The result is returned in `rax`; `u64::MAX` means the call failed.
+//!
+//! The handler runs with interrupts disabled, on the kernel stack of the
+//! calling thread. Since it can only be entered from ring 3, no kernel lock
+//! is held by the interrupted code, but a preempted thread might hold any
+//! lock it doesn't disable interrupts for, so the handler never allocates.
+
+use crate::{
+    memory, print, process,
+    thread::{self, SavedContext},
+    time,
+};
+use x86_64::VirtAddr;
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// `write(ptr, len)`: prints `len` bytes of UTF-8 at `ptr`, returns `len`.
+/// Fails for more than `MAX_WRITE` bytes.
+pub const WRITE: u64 = 0;
+/// `read_key()`: blocks until a key is typed, returns its code point.
+pub const READ_KEY: u64 = 1;
+/// `exit(code)`: ends the process.
+pub const EXIT: u64 = 2;
+/// `sleep(ms)`: blocks for at least `ms` milliseconds, returns 0.
+pub const SLEEP: u64 = 3;
+
+const ERROR: u64 = u64::MAX;
+
+/// Most bytes a `write` call prints, which bounds the time the handler runs
+/// with interrupts disabled.
+pub const MAX_WRITE: u64 = 4096;
+
+/// Length of the `int 0x80` instruction.
+const INT_LEN: u64 = 2;
+
+/// Called by `thread::syscall_interrupt_entry` with the stack pointer to the
+/// saved registers; returns the stack pointer to resume from.
+#[no_mangle]
+extern "C" fn syscall_inner(rsp: u64) -> u64 {
+    let context = unsafe { &mut *(rsp as *mut SavedContext) };
+    let (number, arg0, arg1) = (context.rax, context.rdi, context.rsi);
+
+    context.rax = match number {
+        WRITE => write(arg0, arg1),
+        READ_KEY => match process::next_key() {
+            Some(key) => u64::from(u32::from(key)),
+            None => {
+                // re-execute `int 0x80` once we get to run again
+                context.rip -= INT_LEN;
+                return thread::sleep_current(rsp, time::ticks() + 1);
+            }
+        },
+        EXIT => {
+            process::exit_to_kernel(context, arg0);
+            return rsp;
+        }
+        SLEEP => {
+            context.rax = 0;
+            // `ms_to_ticks` saturates, so a huge `ms` just never wakes up
+            let until = time::ticks().saturating_add(time::ms_to_ticks(arg0));
+            return thread::sleep_current(rsp, until);
+        }
+        _ => ERROR,
+    };
+    rsp
+}
+
+fn write(ptr: u64, len: u64) -> u64 {
+    let start = match VirtAddr::try_new(ptr) {
+        Ok(start) if len <= MAX_WRITE => start,
+        _ => return ERROR,
+    };
+    memory::vma::populate(start, len, false);
+    if !memory::is_user_accessible(start, len, false) {
+        return ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len as usize) };
+    match core::str::from_utf8(bytes) {
+        Ok(text) => {
+            print!("{}", text);
+            len
+        }
+        Err(_) => ERROR,
+    }
+}
@@ -1,13 +1,92 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{join::JoinHandle, Priority, Task, TaskId, TaskInfo, TaskMeta};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::Waker;
 use core::task::{Context, Poll};
 use crossbeam_queue::ArrayQueue;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ReadyQueue>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
+}
+
+/// Slots per priority in a new executor's ready queue.
+pub const INITIAL_QUEUE_CAPACITY: usize = 128;
+
+/// Queues of IDs of tasks that are ready to be polled, one per `Priority`.
+///
+/// Wakeups are coalesced through `TaskMeta::ready`, so every task is queued
+/// at most once and no queue ever needs more slots than there are tasks.
+/// The executor grows them when spawning, with interrupts disabled, so wakers
+/// running in interrupt context never see the write lock held.
+struct ReadyQueue {
+    queues: RwLock<[ArrayQueue<TaskId>; Priority::COUNT]>,
+    /// Set if a push failed; the executor then requeues ready tasks itself.
+    overflowed: AtomicBool,
+}
+
+impl ReadyQueue {
+    fn new(capacity: usize) -> Self {
+        ReadyQueue {
+            queues: RwLock::new([(); Priority::COUNT].map(|_| ArrayQueue::new(capacity))),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues the task unless it is already queued. Never blocks or allocates.
+    fn wake(&self, meta: &TaskMeta) {
+        if !meta.ready.swap(true, Ordering::AcqRel) {
+            self.push(meta.id, meta.priority());
+        }
+    }
+
+    fn push(&self, task_id: TaskId, priority: Priority) {
+        if self.queues.read()[priority as usize].push(task_id).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+
+    fn pop(&self, priority: Priority) -> Option<TaskId> {
+        self.queues.read()[priority as usize].pop().ok()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.read().iter().all(|queue| queue.is_empty())
+    }
+
+    fn capacity(&self) -> usize {
+        self.queues.read()[0].capacity()
+    }
+
+    /// Replaces the queues with ones of at least `capacity` slots.
+    fn grow(&self, capacity: usize) {
+        let new_queues = [(); Priority::COUNT].map(|_| ArrayQueue::new(capacity));
+        interrupts::without_interrupts(|| {
+            let mut queues = self.queues.write();
+            for (queue, new_queue) in queues.iter().zip(new_queues.iter()) {
+                while let Ok(task_id) = queue.pop() {
+                    // cannot fail: the new queue is larger than the old one
+                    let _ = new_queue.push(task_id);
+                }
+            }
+            *queues = new_queues;
+        });
+    }
+}
+
+/// State reachable through a `Spawner`, touched only from task context.
+struct Shared {
+    spawn_queue: Mutex<VecDeque<Task>>,
+    registry: Mutex<BTreeMap<TaskId, Arc<TaskMeta>>>,
 }
 
 impl Default for Executor {
@@ -16,12 +95,147 @@ impl Default for Executor {
     }
 }
 
+/// Cloneable handle for spawning and managing tasks on an `Executor` from
+/// anywhere, including from inside tasks it is currently running.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+    task_queue: Arc<ReadyQueue>,
+}
+
+impl Spawner {
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_named("task", future)
+    }
+
+    pub fn spawn_named<F>(&self, name: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::with_handle(name, future);
+        self.spawn_task(task);
+        handle
+    }
+
+    /// Queues `task` for the executor's next scheduling round. It's listed by
+    /// `tasks` and can be aborted right away.
+    pub fn spawn_task(&self, task: Task) {
+        self.shared
+            .registry
+            .lock()
+            .insert(task.id, task.meta.clone());
+        self.shared.spawn_queue.lock().push_back(task);
+    }
+
+    /// Returns a snapshot of all tasks known to the executor, ordered by ID.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.shared
+            .registry
+            .lock()
+            .values()
+            .map(|meta| meta.info())
+            .collect()
+    }
+
+    /// Requests that the task is dropped before it is polled again.
+    ///
+    /// Returns `false` if no such task exists.
+    pub fn abort(&self, id: TaskId) -> bool {
+        match self.shared.registry.lock().get(&id) {
+            Some(meta) => {
+                meta.aborted.store(true, Ordering::Relaxed);
+                self.task_queue.wake(meta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes the priority of a task, taking effect the next time it is woken.
+    ///
+    /// Returns `false` if no such task exists.
+    pub fn set_priority(&self, id: TaskId, priority: Priority) -> bool {
+        match self.shared.registry.lock().get(&id) {
+            Some(meta) => {
+                meta.set_priority(priority);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+static SPAWNER: Mutex<Option<Spawner>> = Mutex::new(None);
+
+fn with_spawner<R>(f: impl FnOnce(&Spawner) -> R) -> R {
+    f(SPAWNER.lock().as_ref().expect("no executor is alive"))
+}
+
+/// Spawns a future onto the most recently created `Executor`.
+///
+/// Panics if that executor has been dropped.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    with_spawner(|spawner| spawner.spawn(future))
+}
+
+/// Like `spawn`, but gives the task a name shown by `ps`.
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    with_spawner(|spawner| spawner.spawn_named(name, future))
+}
+
+/// Lists the tasks of the most recently created `Executor`.
+pub fn tasks() -> Vec<TaskInfo> {
+    with_spawner(|spawner| spawner.tasks())
+}
+
+/// Aborts a task on the most recently created `Executor`.
+pub fn abort(id: TaskId) -> bool {
+    with_spawner(|spawner| spawner.abort(id))
+}
+
+/// Changes the priority of a task on the most recently created `Executor`.
+pub fn set_priority(id: TaskId, priority: Priority) -> bool {
+    with_spawner(|spawner| spawner.set_priority(id, priority))
+}
+
 impl Executor {
     pub fn new() -> Self {
-        Executor {
+        let executor = Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(ReadyQueue::new(INITIAL_QUEUE_CAPACITY)),
             waker_cache: BTreeMap::new(),
+            shared: Arc::new(Shared {
+                spawn_queue: Mutex::new(VecDeque::new()),
+                registry: Mutex::new(BTreeMap::new()),
+            }),
+        };
+        *SPAWNER.lock() = Some(executor.spawner());
+        executor
+    }
+
+    /// Slots per priority in the ready queue, which grows with the number of
+    /// tasks.
+    pub fn queue_capacity(&self) -> usize {
+        self.task_queue.capacity()
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
+            task_queue: self.task_queue.clone(),
         }
     }
 
@@ -32,12 +246,36 @@ impl Executor {
         }
     }
 
+    /// Runs tasks until every spawned task has completed.
+    pub fn run_until_complete(&mut self) {
+        loop {
+            self.run_ready_tasks();
+            if self.tasks.is_empty() {
+                break;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts::enable_and_hlt;
+
+        if crate::thread::others_runnable() {
+            // let kernel threads use the time instead of halting
+            if self.task_queue.is_empty() {
+                crate::thread::yield_now();
+            }
+            return;
+        }
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty()
+            && !self.task_queue.overflowed.load(Ordering::Acquire)
+            && self.shared.spawn_queue.lock().is_empty()
+        {
+            super::stats::set_idle(true);
             enable_and_hlt();
+            super::stats::set_idle(false);
         } else {
             interrupts::enable();
         }
@@ -45,56 +283,130 @@ impl Executor {
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let priority = task.meta.priority();
+        self.shared
+            .registry
+            .lock()
+            .insert(task_id, task.meta.clone());
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let capacity = self.task_queue.capacity();
+        if self.tasks.len() > capacity {
+            self.task_queue.grow(capacity * 2);
+        }
+        // new tasks start out marked as ready
+        self.task_queue.push(task_id, priority);
+    }
+
+    /// Requeues every ready task after a push to the ready queue failed.
+    ///
+    /// This can only happen if the queue is smaller than the number of tasks,
+    /// which `spawn` prevents, but it keeps wakers from ever having to panic.
+    fn recover_overflow(&mut self) {
+        if !self.task_queue.overflowed.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let capacity = self.task_queue.capacity().max(self.tasks.len());
+        self.task_queue.grow(capacity * 2);
+        // rebuild the queues from the `ready` flags so no task is queued twice
+        for priority in Priority::ALL {
+            while self.task_queue.pop(priority).is_some() {}
+        }
+        for (&task_id, task) in self.tasks.iter() {
+            if task.meta.ready.load(Ordering::Acquire) {
+                self.task_queue.push(task_id, task.meta.priority());
+            }
+        }
+    }
+
+    /// Moves tasks submitted through a `Spawner` into the task map.
+    fn accept_spawned(&mut self) {
+        let shared = self.shared.clone();
+        while let Some(task) = shared.spawn_queue.lock().pop_front() {
+            self.spawn(task);
+        }
     }
 
+    /// Runs one scheduling round.
+    ///
+    /// Each priority level, from highest to lowest, gets to poll up to its
+    /// `Priority::budget` tasks. A task woken during the round is polled again
+    /// in the same round if its level has budget left, but the budget bounds
+    /// the round, so a task that keeps waking itself can't keep this function
+    /// from returning, and higher priorities get their turn in the next one.
     fn run_ready_tasks(&mut self) {
+        self.accept_spawned();
+        self.recover_overflow();
+
+        for priority in Priority::ALL {
+            for _ in 0..priority.budget() {
+                match self.task_queue.pop(priority) {
+                    Some(task_id) => self.run_task(task_id),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn run_task(&mut self, task_id: TaskId) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            shared,
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return, // task no longer exists
+        };
+        if task.meta.aborted.load(Ordering::Relaxed) {
+            // dropping the task also drops its future and wakes its JoinHandle
+            tasks.remove(&task_id);
+            waker_cache.remove(&task_id);
+            shared.registry.lock().remove(&task_id);
+            return;
+        }
+        let waker = waker_cache
+            .entry(task_id)
+            .or_insert_with(|| TaskWaker::new_waker(task.meta.clone(), task_queue.clone()));
+        let mut context = Context::from_waker(waker);
+        match task.poll(&mut context) {
+            Poll::Ready(()) => {
+                // task done -> remove it and its cached waker
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+                shared.registry.lock().remove(&task_id);
             }
+            Poll::Pending => {}
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // tasks spawned through the global functions would never run
+        let mut spawner = SPAWNER.lock();
+        if matches!(&*spawner, Some(global) if Arc::ptr_eq(&global.shared, &self.shared)) {
+            *spawner = None;
         }
     }
 }
 
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    meta: Arc<TaskMeta>,
+    task_queue: Arc<ReadyQueue>,
 }
 
 impl TaskWaker {
-    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn new_waker(meta: Arc<TaskMeta>, task_queue: Arc<ReadyQueue>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { meta, task_queue }))
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.task_queue.wake(&self.meta);
     }
 }
 
@@ -0,0 +1,120 @@
+use super::TaskId;
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    aborted: bool,
+    waker: Option<Waker>,
+}
+
+/// Error returned by a `JoinHandle` whose task was aborted before finishing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinError;
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task was aborted")
+    }
+}
+
+/// Future resolving to the output of a spawned task.
+///
+/// Dropping the handle detaches the task; it keeps running in the background.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+/// The task side of a `JoinHandle`, storing the output once it is produced.
+///
+/// If it is dropped without completing, the task was aborted.
+pub(super) struct Completer<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+pub(super) fn pair<T>() -> (JoinHandle<T>, Completer<T>) {
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        finished: false,
+        aborted: false,
+        waker: None,
+    }));
+    (
+        JoinHandle {
+            id: TaskId(0),
+            state: state.clone(),
+        },
+        Completer { state },
+    )
+}
+
+impl<T> Completer<T> {
+    pub(super) fn complete(self, output: T) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.output = Some(output);
+            state.finished = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.finished {
+                return;
+            }
+            state.aborted = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn with_id(mut self, id: TaskId) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// ID of the task, usable with `executor::abort`.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns `true` if the task has produced its output.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(output) = state.output.take() {
+            Poll::Ready(Ok(output))
+        } else if state.aborted {
+            Poll::Ready(Err(JoinError))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
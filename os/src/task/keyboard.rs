@@ -1,4 +1,4 @@
-use crate::println;
+use crate::{irq, println};
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
@@ -10,7 +10,25 @@ use futures_util::task::AtomicWaker;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
-pub(crate) fn add_scancode(scancode: u8) {
+/// ISA interrupt line of the PS/2 keyboard.
+const KEYBOARD_IRQ: u8 = 1;
+
+/// Registers the keyboard interrupt handler.
+pub fn init() {
+    irq::register(KEYBOARD_IRQ, "keyboard", interrupt_handler)
+        .expect("keyboard interrupt line taken");
+}
+
+fn interrupt_handler(_irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    add_scancode(scancode);
+    true
+}
+
+fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if queue.push(scancode).is_err() {
             println!("WARNING: scancode queue full; dropping keyboard input");
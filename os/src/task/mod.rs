@@ -1,35 +1,246 @@
-use alloc::boxed::Box;
-use core::{future::Future, pin::Pin};
+use alloc::{borrow::ToOwned, boxed::Box, string::String, sync::Arc};
+use core::{fmt, future::Future, pin::Pin};
 pub mod executor;
+pub mod join;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod stats;
+pub mod sync;
+pub mod timer;
 use core::task::{Context, Poll};
+use join::JoinHandle;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for TaskId {
+    fn from(id: u64) -> Self {
+        TaskId(id)
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken and waiting in the ready queue.
+    Ready,
+    /// Waiting for a waker to be called.
+    Pending,
+}
+
+/// Scheduling class of a task. Higher priorities are polled first and get a
+/// larger share of every scheduling round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    /// Tasks the user is waiting on, like the shell.
+    Interactive = 0,
+    Normal = 1,
+    /// Long-running jobs that should only use otherwise idle time.
+    Background = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+    pub const ALL: [Priority; Priority::COUNT] = [
+        Priority::Interactive,
+        Priority::Normal,
+        Priority::Background,
+    ];
+
+    /// Maximum number of polls this level gets per scheduling round.
+    pub fn budget(self) -> usize {
+        match self {
+            Priority::Interactive => 32,
+            Priority::Normal => 8,
+            Priority::Background => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::Interactive,
+            1 => Priority::Normal,
+            _ => Priority::Background,
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Priority::Interactive => "interactive",
+            Priority::Normal => "normal",
+            Priority::Background => "background",
+        })
+    }
+}
+
+/// Bookkeeping shared between a task, its waker and the executor's registry.
+///
+/// Only atomics are touched from wakers, since they may run in interrupt context.
+struct TaskMeta {
+    id: TaskId,
+    name: String,
+    created: u64,
+    priority: AtomicU8,
+    polls: AtomicU64,
+    /// Timer ticks during which this task was being polled.
+    cpu_ticks: AtomicU64,
+    /// Set while the task sits in the ready queue, so wakeups are coalesced.
+    ready: AtomicBool,
+    aborted: AtomicBool,
+}
+
+impl TaskMeta {
+    fn priority(&self) -> Priority {
+        Priority::from_u8(self.priority.load(Ordering::Relaxed))
+    }
+
+    fn set_priority(&self, priority: Priority) {
+        self.priority.store(priority as u8, Ordering::Relaxed);
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            state: if self.ready.load(Ordering::Relaxed) {
+                TaskState::Ready
+            } else {
+                TaskState::Pending
+            },
+            priority: self.priority(),
+            polls: self.polls.load(Ordering::Relaxed),
+            cpu_ticks: self.cpu_ticks.load(Ordering::Relaxed),
+            created: self.created,
+        }
+    }
+}
+
+/// Snapshot of a task's bookkeeping, as returned by `executor::tasks`.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub state: TaskState,
+    pub priority: Priority,
+    pub polls: u64,
+    /// Timer ticks spent polling the task.
+    pub cpu_ticks: u64,
+    /// Tick count at the time the task was created.
+    pub created: u64,
 }
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    meta: Arc<TaskMeta>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    /// Creates a task whose output is discarded.
+    pub fn new<T>(future: impl Future<Output = T> + Send + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    /// Creates a task with a name shown by `ps`.
+    pub fn named<T>(name: &str, future: impl Future<Output = T> + Send + 'static) -> Task {
+        let id = TaskId::new();
         Task {
-            id: TaskId::new(),
-            future: Box::pin(future),
+            id,
+            meta: Arc::new(TaskMeta {
+                id,
+                name: name.to_owned(),
+                created: crate::time::ticks(),
+                priority: AtomicU8::new(Priority::Normal as u8),
+                polls: AtomicU64::new(0),
+                cpu_ticks: AtomicU64::new(0),
+                ready: AtomicBool::new(true),
+                aborted: AtomicBool::new(false),
+            }),
+            future: Box::pin(async move {
+                future.await;
+            }),
         }
     }
+
+    /// Creates a task together with a handle that resolves to its output.
+    pub fn with_handle<T: Send + 'static>(
+        name: &str,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> (Task, JoinHandle<T>) {
+        let (handle, completer) = join::pair();
+        let task = Task::named(name, async move {
+            completer.complete(future.await);
+        });
+        let handle = handle.with_id(task.id);
+        (task, handle)
+    }
+
+    /// Sets the scheduling priority; tasks default to `Priority::Normal`.
+    pub fn with_priority(self, priority: Priority) -> Task {
+        self.meta.set_priority(priority);
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.meta.name
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        self.meta.ready.store(false, Ordering::Release);
+        self.meta.polls.fetch_add(1, Ordering::Relaxed);
+        stats::enter_task(&self.meta);
+        let result = self.future.as_mut().poll(context);
+        stats::leave_task();
+        result
+    }
+}
+
+/// Future that returns `Pending` once after waking its own task, giving other
+/// tasks a chance to run before it continues.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 }
+
+/// Cooperatively gives up the CPU; long-running tasks should await this
+/// regularly so they don't hold up keyboard handling.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
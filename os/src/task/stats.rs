@@ -0,0 +1,85 @@
+//! CPU usage accounting, sampled by the timer interrupt.
+//!
+//! On every tick the interrupt handler checks what the CPU was doing when it
+//! was interrupted: halted in `Executor::sleep_if_idle`, polling a task,
+//! running a kernel thread, or running other executor or interrupt code.
+//! Sampling keeps the bookkeeping out of the hot path and still adds up to
+//! accurate figures over time.
+
+use super::{TaskId, TaskMeta};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+static BUSY_TICKS: AtomicU64 = AtomicU64::new(0);
+static IDLE: AtomicBool = AtomicBool::new(false);
+/// Task currently being polled, or null.
+static CURRENT: AtomicPtr<TaskMeta> = AtomicPtr::new(ptr::null_mut());
+
+/// Ticks spent halted and running since boot.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    pub idle_ticks: u64,
+    pub busy_ticks: u64,
+}
+
+impl CpuStats {
+    /// Percentage of ticks spent busy between `earlier` and `self`.
+    pub fn load_since(&self, earlier: &CpuStats) -> u64 {
+        let busy = self.busy_ticks - earlier.busy_ticks;
+        let total = busy + self.idle_ticks - earlier.idle_ticks;
+        if total == 0 {
+            0
+        } else {
+            busy * 100 / total
+        }
+    }
+}
+
+pub fn cpu_stats() -> CpuStats {
+    CpuStats {
+        idle_ticks: IDLE_TICKS.load(Ordering::Relaxed),
+        busy_ticks: BUSY_TICKS.load(Ordering::Relaxed),
+    }
+}
+
+/// Called from the timer interrupt handler.
+pub(crate) fn on_tick() {
+    if !crate::thread::on_boot_thread() {
+        // another kernel thread was running, not the executor
+        BUSY_TICKS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if IDLE.load(Ordering::Relaxed) {
+        IDLE_TICKS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    BUSY_TICKS.fetch_add(1, Ordering::Relaxed);
+    let current = CURRENT.load(Ordering::Acquire);
+    // safety: `current` is only non-null while `Task::poll` holds the `Arc`
+    if let Some(meta) = unsafe { current.as_ref() } {
+        meta.cpu_ticks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Calls `f` with the id and name of the task being polled, if any. For
+/// fault handlers reporting what was running on the boot thread.
+pub(crate) fn with_current_task<R>(f: impl FnOnce(Option<(TaskId, &str)>) -> R) -> R {
+    let current = CURRENT.load(Ordering::Acquire);
+    // safety: see `on_tick`
+    f(unsafe { current.as_ref() }.map(|meta| (meta.id, meta.name.as_str())))
+}
+
+/// Marks the CPU as halted until the next interrupt.
+pub(super) fn set_idle(idle: bool) {
+    IDLE.store(idle, Ordering::Relaxed);
+}
+
+/// Attributes ticks to `meta` until `leave_task` is called.
+pub(super) fn enter_task(meta: &TaskMeta) {
+    CURRENT.store(meta as *const TaskMeta as *mut TaskMeta, Ordering::Release);
+}
+
+pub(super) fn leave_task() {
+    CURRENT.store(ptr::null_mut(), Ordering::Release);
+}
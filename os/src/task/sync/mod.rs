@@ -0,0 +1,45 @@
+//! Async synchronization primitives for tasks running on the `Executor`.
+//!
+//! Unlike `spin::Mutex`, nothing here busy-waits: a task that can't make
+//! progress registers its waker and returns `Pending`. Internal spin locks are
+//! only held for a few instructions and never across an `.await`.
+
+use alloc::vec::Vec;
+use core::task::Waker;
+
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+
+/// Wakers of tasks waiting for some condition.
+///
+/// All waiters are woken together and re-check the condition when polled,
+/// which keeps cancellation simple: a dropped future can never swallow a
+/// wakeup meant for somebody else.
+struct WakerList {
+    wakers: Vec<Waker>,
+}
+
+impl WakerList {
+    const fn new() -> Self {
+        WakerList { wakers: Vec::new() }
+    }
+
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    /// Consumes the wakers, which may free their tasks: not for interrupt
+    /// handlers.
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
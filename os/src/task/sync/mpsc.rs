@@ -0,0 +1,265 @@
+use super::WakerList;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::{future::poll_fn, stream::Stream};
+use spin::Mutex;
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// `None` for unbounded channels.
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    recv_waker: Option<Waker>,
+    send_waiters: WakerList,
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        matches!(self.capacity, Some(capacity) if self.queue.len() >= capacity)
+    }
+}
+
+struct Chan<T> {
+    state: Mutex<State<T>>,
+}
+
+impl<T> Chan<T> {
+    fn new(capacity: Option<usize>) -> Arc<Self> {
+        Arc::new(Chan {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                capacity,
+                senders: 1,
+                receiver_alive: true,
+                recv_waker: None,
+                send_waiters: WakerList::new(),
+            }),
+        })
+    }
+
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let waker = {
+            let mut state = self.state.lock();
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if state.is_full() {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+            state.recv_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn poll_send(&self, cx: &mut Context, value: &mut Option<T>) -> Poll<Result<(), SendError<T>>> {
+        let waker = {
+            let mut state = self.state.lock();
+            let item = value.take().expect("polled after completion");
+            if !state.receiver_alive {
+                return Poll::Ready(Err(SendError(item)));
+            }
+            if state.is_full() {
+                *value = Some(item);
+                state.send_waiters.register(cx.waker());
+                return Poll::Pending;
+            }
+            state.queue.push_back(item);
+            state.recv_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_recv(&self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.queue.pop_front() {
+            state.send_waiters.wake_all();
+            Poll::Ready(Some(value))
+        } else if state.senders == 0 {
+            Poll::Ready(None)
+        } else {
+            state.recv_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.senders -= 1;
+            if state.senders > 0 {
+                return;
+            }
+            state.recv_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock();
+        state.receiver_alive = false;
+        state.queue.clear();
+        state.send_waiters.wake_all();
+    }
+}
+
+/// Error returned by `send` when the receiver has been dropped.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+/// Error returned by `try_send`.
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// The receiver has been dropped.
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+/// Sending half of a bounded channel. `send` waits while the channel is full.
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Sending half of an unbounded channel. `send` never waits.
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Receiving half of a channel. Yields `None` once all senders are gone and
+/// the queue is drained.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Creates a channel that buffers at most `capacity` values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let chan = Chan::new(Some(capacity));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Creates a channel that buffers any number of values.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, waiting for room if the channel is full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        poll_fn(|cx| self.chan.poll_send(cx, &mut value)).await
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.try_send(value).map_err(|err| match err {
+            TrySendError::Full(value) | TrySendError::Closed(value) => SendError(value),
+        })
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.chan.poll_recv(cx)).await
+    }
+
+    /// Returns a value if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let mut state = self.chan.state.lock();
+        let value = state.queue.pop_front();
+        if value.is_some() {
+            state.send_waiters.wake_all();
+        }
+        value
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.chan.poll_recv(cx)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.close();
+    }
+}
@@ -0,0 +1,104 @@
+use super::WakerList;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+struct State {
+    locked: bool,
+    waiters: WakerList,
+}
+
+/// Mutex whose `lock` yields to other tasks instead of spinning, so it can
+/// be held across `.await` points.
+pub struct Mutex<T> {
+    state: spin::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: spin::Mutex::new(State {
+                locked: false,
+                waiters: WakerList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Waits until the lock is free and acquires it.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let mut state = self.mutex.state.lock();
+        if state.locked {
+            state.waiters.register(cx.waker());
+            Poll::Pending
+        } else {
+            state.locked = true;
+            Poll::Ready(MutexGuard { mutex: self.mutex })
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+// a shared guard hands out `&T`, which `&Mutex<T>` alone would allow for
+// any `T: Send`
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        state.waiters.wake_all();
+    }
+}
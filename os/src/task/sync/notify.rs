@@ -0,0 +1,95 @@
+use super::WakerList;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use x86_64::instructions::interrupts;
+
+struct State {
+    /// Stored by `notify_one` and consumed by exactly one `notified` future.
+    permit: bool,
+    /// Bumped by `notify_waiters` to release every future created before it.
+    generation: u64,
+    waiters: WakerList,
+}
+
+/// Lets one task signal others that something happened, without any data.
+///
+/// The notify methods never allocate, but a woken waker may drop the last
+/// reference to its task and free it, so they must not be called from
+/// interrupt handlers.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                generation: 0,
+                waiters: WakerList::new(),
+            }),
+        }
+    }
+
+    /// Waits for a call to `notify_one` or `notify_waiters`.
+    pub fn notified(&self) -> Notified<'_> {
+        let generation = interrupts::without_interrupts(|| self.state.lock().generation);
+        Notified {
+            notify: self,
+            generation,
+        }
+    }
+
+    /// Wakes one waiting task. If nobody is waiting, the next call to
+    /// `notified` completes immediately.
+    pub fn notify_one(&self) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.permit = true;
+            state.waiters.wake_all();
+        });
+    }
+
+    /// Wakes every task currently waiting, without storing a permit.
+    pub fn notify_waiters(&self) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.generation += 1;
+            state.waiters.wake_all();
+        });
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    generation: u64,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+            if state.generation != self.generation {
+                Poll::Ready(())
+            } else if state.permit {
+                state.permit = false;
+                Poll::Ready(())
+            } else {
+                state.waiters.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+}
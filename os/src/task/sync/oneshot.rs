@@ -0,0 +1,110 @@
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+struct State<T> {
+    value: Option<T>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// Error returned when the `Sender` was dropped without sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+/// Future resolving to the value sent through the matching `Sender`.
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+/// Creates a channel for sending a single value between tasks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        value: None,
+        closed: false,
+        waker: None,
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, handing it back if the `Receiver` is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.closed {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.closed = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the `Receiver` has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.closed = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.value.take() {
+            Poll::Ready(Ok(value))
+        } else if state.closed {
+            Poll::Ready(Err(RecvError))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        // drop a value that was sent but never received
+        state.value = None;
+    }
+}
@@ -0,0 +1,204 @@
+use crate::time;
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const WHEEL_SIZE: usize = 256;
+
+struct Entry {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+    fired: bool,
+}
+
+/// Hashed timer wheel with one slot per tick, indexed by `deadline % WHEEL_SIZE`.
+///
+/// Entries are only inserted and removed from task context with interrupts
+/// disabled, so the interrupt handler never allocates or frees memory and
+/// never contends on the lock.
+struct TimerWheel {
+    slots: [Vec<Entry>; WHEEL_SIZE],
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        const EMPTY: Vec<Entry> = Vec::new();
+        TimerWheel {
+            slots: [EMPTY; WHEEL_SIZE],
+        }
+    }
+
+    fn slot(deadline: u64) -> usize {
+        (deadline % WHEEL_SIZE as u64) as usize
+    }
+
+    fn register(&mut self, id: u64, deadline: u64, waker: &Waker) {
+        let slot = &mut self.slots[Self::slot(deadline)];
+        match slot.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => {
+                if !entry.waker.will_wake(waker) {
+                    entry.waker = waker.clone();
+                }
+                entry.fired = false;
+            }
+            None => slot.push(Entry {
+                id,
+                deadline,
+                waker: waker.clone(),
+                fired: false,
+            }),
+        }
+    }
+
+    fn remove(&mut self, id: u64, deadline: u64) {
+        let slot = &mut self.slots[Self::slot(deadline)];
+        if let Some(idx) = slot.iter().position(|entry| entry.id == id) {
+            slot.swap_remove(idx);
+        }
+    }
+
+    /// Wakes every entry in the current slot whose deadline has passed.
+    ///
+    /// Woken entries stay in the wheel until their future removes them.
+    fn fire(&mut self, now: u64) {
+        for entry in self.slots[Self::slot(now)].iter_mut() {
+            if !entry.fired && entry.deadline <= now {
+                entry.fired = true;
+                entry.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// Called from the timer interrupt handler after the tick counter advanced.
+pub(crate) fn on_tick() {
+    WHEEL.lock().fire(time::ticks());
+}
+
+fn next_timer_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Future that completes once the tick counter reaches its deadline.
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    fn until(deadline: u64) -> Self {
+        Sleep {
+            id: next_timer_id(),
+            deadline,
+            registered: false,
+        }
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    fn reset(&mut self, deadline: u64) {
+        self.deregister();
+        self.deadline = deadline;
+    }
+
+    fn deregister(&mut self) {
+        if self.registered {
+            interrupts::without_interrupts(|| WHEEL.lock().remove(self.id, self.deadline));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let ready = interrupts::without_interrupts(|| {
+            if time::ticks() >= this.deadline {
+                return true;
+            }
+            WHEEL.lock().register(this.id, this.deadline, cx.waker());
+            false
+        });
+
+        if ready {
+            this.deregister();
+            Poll::Ready(())
+        } else {
+            this.registered = true;
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep(ms: u64) -> Sleep {
+    Sleep::until(time::ticks().saturating_add(time::ms_to_ticks(ms)))
+}
+
+/// Waits until the tick counter reaches `tick`.
+pub fn sleep_until(tick: u64) -> Sleep {
+    Sleep::until(tick)
+}
+
+/// Stream that yields the current tick count once every period.
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+impl Interval {
+    pub fn period_ticks(&self) -> u64 {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let now = time::ticks();
+                // skip missed periods instead of firing them back to back
+                let mut next = self.sleep.deadline.saturating_add(self.period);
+                if next <= now {
+                    next = now.saturating_add(self.period);
+                }
+                self.sleep.reset(next);
+                Poll::Ready(Some(now))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Yields every `ms` milliseconds, starting `ms` milliseconds from now.
+pub fn interval(ms: u64) -> Interval {
+    let period = time::ms_to_ticks(ms).max(1);
+    Interval {
+        period,
+        sleep: Sleep::until(time::ticks().saturating_add(period)),
+    }
+}
@@ -0,0 +1,580 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack, with an unmapped guard page below it so an
+//! overflow faults instead of corrupting other memory. The timer interrupt
+//! enters through `timer_interrupt_entry`, which pushes all general purpose
+//! registers onto the interrupted stack and hands the resulting stack
+//! pointer to `preempt`. Switching threads is then just returning a
+//! different stack pointer, from which the entry stub pops the registers and
+//! `iretq`s.
+//!
+//! The thread that called `init` (the one running `kernel_main` and the async
+//! `Executor`) becomes thread 0 and keeps using the bootloader's stack.
+//!
+//! The scheduler runs in interrupt context, where the interrupted thread might
+//! hold the allocator lock, so it never allocates or frees memory. Threads are
+//! kept in a fixed table and finished ones are freed by `reap` from thread
+//! context.
+//!
+//! Threads spawned for user processes drop to ring 3 after starting. The CPU
+//! switches back to the top of the thread's own stack on every interrupt from
+//! ring 3, so the scheduler points the TSS at it before resuming the thread.
+//! It also loads the page table of the thread's process, or the kernel's for
+//! kernel threads.
+
+use crate::{
+    allocator::slab::{ObjectCache, SlabBox},
+    gdt,
+    memory::{self, stack::KernelStack},
+    process::Process,
+    time,
+};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    arch::global_asm,
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame, VirtAddr,
+};
+
+pub const MAX_THREADS: usize = 32;
+pub const STACK_SIZE: usize = 4096 * 4;
+/// Number of timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Runnable,
+    /// Not scheduled until the tick counter reaches the given value.
+    Sleeping(u64),
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    /// Shared so `threads` can copy it without allocating.
+    name: Arc<str>,
+    state: ThreadState,
+    /// Stack pointer to the saved registers while the thread isn't running.
+    rsp: u64,
+    /// Timer ticks this thread was running for.
+    ticks: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack.
+    stack: Option<KernelStack>,
+    /// The user process running on this thread, if any.
+    process: Option<Arc<Process>>,
+    /// Level 4 table to run on; `None` for the kernel's.
+    page_table: Option<PhysFrame>,
+}
+
+impl Thread {
+    fn stack_top(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(KernelStack::top)
+    }
+}
+
+/// Snapshot of a thread's bookkeeping, as returned by `threads`.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: Arc<str>,
+    pub state: ThreadState,
+    pub ticks: u64,
+}
+
+struct Scheduler {
+    threads: [Option<SlabBox<Thread>>; MAX_THREADS],
+    current: usize,
+    slice_left: u64,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        const EMPTY: Option<SlabBox<Thread>> = None;
+        Scheduler {
+            threads: [EMPTY; MAX_THREADS],
+            current: 0,
+            slice_left: TIME_SLICE_TICKS,
+        }
+    }
+
+    fn is_runnable(&self, index: usize) -> bool {
+        match &self.threads[index] {
+            Some(thread) => match thread.state {
+                ThreadState::Runnable => true,
+                ThreadState::Sleeping(until) => time::ticks() >= until,
+                ThreadState::Finished => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Saves `rsp` for the current thread and returns the stack pointer of
+    /// the next runnable one, round-robin.
+    fn switch(&mut self, rsp: u64) -> u64 {
+        let current = match self.threads[self.current].as_mut() {
+            Some(thread) => thread,
+            // not initialized yet
+            None => return rsp,
+        };
+        current.rsp = rsp;
+
+        for offset in 1..=MAX_THREADS {
+            let index = (self.current + offset) % MAX_THREADS;
+            if self.is_runnable(index) {
+                self.current = index;
+                CURRENT_INDEX.store(index, Ordering::Relaxed);
+                self.slice_left = TIME_SLICE_TICKS;
+                let next = self.threads[index].as_mut().unwrap();
+                next.state = ThreadState::Runnable;
+                if let Some(top) = next.stack_top() {
+                    gdt::set_kernel_stack(top);
+                }
+                load_page_table(next.page_table);
+                return next.rsp;
+            }
+        }
+        // not even the current thread is runnable, which can't happen since
+        // thread 0 never finishes or sleeps
+        rsp
+    }
+}
+
+fn load_page_table(page_table: Option<PhysFrame>) {
+    let page_table = page_table.unwrap_or_else(memory::kernel_page_table);
+    let (current, flags) = Cr3::read();
+    if current != page_table {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+/// Where the bookkeeping of all threads lives, shown by `slabs`.
+static THREADS: ObjectCache<Thread> = ObjectCache::new("thread");
+/// Table index of the running thread, readable without the scheduler lock.
+static CURRENT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the calling code as thread 0.
+pub fn init() {
+    let boot_thread = THREADS
+        .alloc(Thread {
+            id: ThreadId(0),
+            name: Arc::from("kernel"),
+            state: ThreadState::Runnable,
+            rsp: 0,
+            ticks: 0,
+            stack: None,
+            process: None,
+            page_table: None,
+        })
+        .expect("no memory for the boot thread");
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads[0] = Some(boot_thread);
+        scheduler.current = 0;
+    });
+}
+
+/// Returns `true` while the boot thread, which runs the async executor, is
+/// the one on the CPU.
+pub fn on_boot_thread() -> bool {
+    CURRENT_INDEX.load(Ordering::Relaxed) == 0
+}
+
+/// Registers pushed by the interrupt entry stubs, followed by the frame the
+/// CPU pushed on interrupt entry. New threads start with a forged one.
+#[repr(C)]
+pub(crate) struct SavedContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+type ThreadMain = Box<dyn FnOnce() + Send + 'static>;
+
+extern "C" fn thread_entry(main: *mut ThreadMain) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit();
+}
+
+/// Spawns a kernel thread running `f`.
+///
+/// Returns `None` if `MAX_THREADS` threads are already alive.
+pub fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> Option<ThreadId> {
+    spawn_inner(name, None, Box::new(f))
+}
+
+/// Spawns the thread a user process runs on, in the address space whose
+/// level 4 table is `page_table`. `f` is expected to drop to ring 3 and
+/// never return.
+pub(crate) fn spawn_process(
+    process: Arc<Process>,
+    page_table: PhysFrame,
+    f: impl FnOnce() + Send + 'static,
+) -> Option<ThreadId> {
+    let owner = Arc::clone(&process);
+    spawn_inner(owner.name(), Some((process, page_table)), Box::new(f))
+}
+
+fn spawn_inner(
+    name: &str,
+    process: Option<(Arc<Process>, PhysFrame)>,
+    f: ThreadMain,
+) -> Option<ThreadId> {
+    use x86_64::registers::segmentation::{Segment, CS, SS};
+
+    reap();
+
+    let stack = KernelStack::new(STACK_SIZE).expect("failed to allocate a thread stack");
+    let main: *mut ThreadMain = Box::into_raw(Box::new(f));
+
+    // the stack pointer has to be 16-byte aligned before a `call`; we emulate
+    // one that pushed a null return address
+    let stack_top = stack.top().as_u64();
+    let entry_rsp = stack_top - 8;
+    let context_addr = entry_rsp - core::mem::size_of::<SavedContext>() as u64;
+    unsafe {
+        (entry_rsp as *mut u64).write(0);
+        (context_addr as *mut SavedContext).write(SavedContext {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: main as u64,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            rip: thread_entry as usize as u64,
+            cs: u64::from(CS::get_reg().0),
+            rflags: 0x202, // interrupts enabled
+            rsp: entry_rsp,
+            ss: u64::from(SS::get_reg().0),
+        });
+    }
+
+    let id = ThreadId::new();
+    let thread = THREADS
+        .alloc(Thread {
+            id,
+            name: Arc::from(name),
+            state: ThreadState::Runnable,
+            rsp: context_addr,
+            ticks: 0,
+            stack: Some(stack),
+            page_table: process.as_ref().map(|(_, page_table)| *page_table),
+            process: process.map(|(process, _)| process),
+        })
+        .expect("no memory for a thread");
+
+    let rejected = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        match scheduler.threads.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(thread);
+                None
+            }
+            None => Some(thread),
+        }
+    });
+    if rejected.is_some() {
+        // the thread never ran, so the closure is still ours to free
+        drop(unsafe { Box::from_raw(main) });
+        return None;
+    }
+    Some(id)
+}
+
+/// Frees the stacks of finished threads.
+pub fn reap() {
+    let mut finished = [(); MAX_THREADS].map(|_| None);
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        for (index, slot) in scheduler.threads.iter_mut().enumerate() {
+            if index != current
+                && matches!(slot, Some(thread) if thread.state == ThreadState::Finished)
+            {
+                finished[index] = slot.take();
+            }
+        }
+    });
+    // dropped here, with interrupts enabled
+    drop(finished);
+}
+
+/// Ends the calling thread.
+pub fn exit() -> ! {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        assert!(current != 0, "the boot thread cannot exit");
+        if let Some(thread) = scheduler.threads[current].as_mut() {
+            thread.state = ThreadState::Finished;
+        }
+    });
+    loop {
+        yield_now();
+    }
+}
+
+/// Gives the rest of the time slice to the next runnable thread.
+pub fn yield_now() {
+    // must match `YIELD_VECTOR`
+    unsafe { core::arch::asm!("int 0x81") };
+}
+
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[scheduler.current]
+            .as_ref()
+            .map_or(ThreadId(0), |thread| thread.id)
+    })
+}
+
+/// Id and name of the running thread, for fault handlers. `None` if the
+/// scheduler lock is held.
+pub(crate) fn try_current() -> Option<(ThreadId, Arc<str>)> {
+    let scheduler = SCHEDULER.try_lock()?;
+    let thread = scheduler.threads[scheduler.current].as_ref()?;
+    Some((thread.id, thread.name.clone()))
+}
+
+/// Returns `true` if any thread besides the calling one can run.
+pub fn others_runnable() -> bool {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        (0..MAX_THREADS).any(|index| index != scheduler.current && scheduler.is_runnable(index))
+    })
+}
+
+/// Returns `true` if the thread has not finished yet.
+pub fn is_alive(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .threads
+            .iter()
+            .flatten()
+            .any(|thread| thread.id == id && thread.state != ThreadState::Finished)
+    })
+}
+
+/// Moves the calling thread back to the kernel's address space, so the one
+/// it ran on can be dropped.
+pub(crate) fn leave_address_space() {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        if let Some(thread) = scheduler.threads[current].as_mut() {
+            thread.page_table = None;
+        }
+        load_page_table(None);
+    });
+}
+
+/// The user process running on the calling thread, if any.
+pub(crate) fn current_process() -> Option<Arc<Process>> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[scheduler.current]
+            .as_ref()
+            .and_then(|thread| thread.process.clone())
+    })
+}
+
+/// Yields until the thread has finished.
+pub fn join(id: ThreadId) {
+    while is_alive(id) {
+        yield_now();
+    }
+    reap();
+}
+
+pub fn threads() -> Vec<ThreadInfo> {
+    // allocate up front: with interrupts disabled, a preempted thread holding
+    // the allocator lock could never release it
+    let mut infos = Vec::with_capacity(MAX_THREADS);
+    interrupts::without_interrupts(|| {
+        for thread in SCHEDULER.lock().threads.iter().flatten() {
+            infos.push(ThreadInfo {
+                id: thread.id,
+                name: thread.name.clone(),
+                state: thread.state,
+                ticks: thread.ticks,
+            });
+        }
+    });
+    infos
+}
+
+/// Vector of the software interrupt used by `yield_now`.
+pub const YIELD_VECTOR: u8 = 0x81;
+
+/// Called by the timer entry stub after the tick was accounted for.
+///
+/// Returns the stack pointer to resume, which belongs to another thread once
+/// the current one used up its time slice.
+pub(crate) fn preempt(rsp: u64) -> u64 {
+    // the lock is only taken with interrupts disabled, so it is never held here
+    let mut scheduler = match SCHEDULER.try_lock() {
+        Some(scheduler) => scheduler,
+        None => return rsp,
+    };
+    let current = scheduler.current;
+    if let Some(thread) = scheduler.threads[current].as_mut() {
+        thread.ticks += 1;
+    }
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    if scheduler.slice_left > 0 {
+        return rsp;
+    }
+    scheduler.switch(rsp)
+}
+
+/// Puts the interrupted thread to sleep until the tick counter reaches
+/// `until` and returns the stack pointer to resume instead.
+///
+/// Only for handlers of software interrupts raised in ring 3, where the
+/// scheduler lock can't be held.
+pub(crate) fn sleep_current(rsp: u64, until: u64) -> u64 {
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current;
+    if let Some(thread) = scheduler.threads[current].as_mut() {
+        thread.state = ThreadState::Sleeping(until);
+    }
+    scheduler.switch(rsp)
+}
+
+#[no_mangle]
+extern "C" fn yield_interrupt_inner(rsp: u64) -> u64 {
+    match SCHEDULER.try_lock() {
+        Some(mut scheduler) => scheduler.switch(rsp),
+        None => rsp,
+    }
+}
+
+// All stubs save all general purpose registers, pass the stack pointer to a
+// Rust function and resume on whatever stack pointer it returns. With the five
+// words pushed by the CPU, the 15 pushes leave the stack 16-byte aligned for
+// the call.
+global_asm!(
+    r#"
+.macro PUSH_REGS
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+.endm
+
+.macro POP_REGS
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+.endm
+
+.global timer_interrupt_entry
+timer_interrupt_entry:
+    PUSH_REGS
+    mov rdi, rsp
+    call timer_interrupt_inner
+    mov rsp, rax
+    POP_REGS
+    iretq
+
+.global yield_interrupt_entry
+yield_interrupt_entry:
+    PUSH_REGS
+    mov rdi, rsp
+    call yield_interrupt_inner
+    mov rsp, rax
+    POP_REGS
+    iretq
+
+.global syscall_interrupt_entry
+syscall_interrupt_entry:
+    PUSH_REGS
+    mov rdi, rsp
+    call syscall_inner
+    mov rsp, rax
+    POP_REGS
+    iretq
+"#
+);
+
+extern "C" {
+    pub(crate) fn timer_interrupt_entry();
+    pub(crate) fn yield_interrupt_entry();
+    pub(crate) fn syscall_interrupt_entry();
+}
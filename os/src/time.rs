@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+/// Base frequency of the PIT oscillator in Hz.
+pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+/// Frequency the PIT is programmed to fire the timer interrupt at.
+pub const TIMER_FREQUENCY: u32 = 1000;
+
+const PIT_DIVISOR: u16 = (PIT_BASE_FREQUENCY / TIMER_FREQUENCY) as u16;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Gate of channel 2 in bit 0, its output in bit 5, and the speaker enable
+/// bit 1.
+const PIT_CHANNEL_2_CONTROL: u16 = 0x61;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 of the PIT to fire at `TIMER_FREQUENCY`.
+///
+/// Must be called before interrupts are enabled.
+pub fn init() {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0);
+
+    unsafe {
+        // channel 0, access mode lobyte/hibyte, mode 3 (square wave), binary
+        command.write(0b0011_0110);
+        channel_0.write((PIT_DIVISOR & 0xff) as u8);
+        channel_0.write((PIT_DIVISOR >> 8) as u8);
+    }
+}
+
+/// Busy-waits for `ms` milliseconds, at most 54, on channel 2 of the PIT.
+///
+/// Doesn't need interrupts, so it can calibrate other timers.
+pub fn pit_delay(ms: u32) {
+    assert!(ms <= 54, "PIT delays are limited to 54 ms");
+    let count = (PIT_BASE_FREQUENCY as u64 * u64::from(ms) / 1000) as u16;
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_2 = Port::<u8>::new(PIT_CHANNEL_2);
+    let mut control = Port::<u8>::new(PIT_CHANNEL_2_CONTROL);
+
+    unsafe {
+        // gate low and speaker off while programming
+        let gate = control.read() & !0b11;
+        control.write(gate);
+        // channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        channel_2.write((count & 0xff) as u8);
+        channel_2.write((count >> 8) as u8);
+        // counting starts when the gate goes high, the output follows at zero
+        control.write(gate | 1);
+        while control.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(gate);
+    }
+}
+
+/// Called from the timer interrupt handler on every tick.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time elapsed since boot.
+pub fn uptime() -> Duration {
+    Duration::from_millis(ticks_to_ms(ticks()))
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / TIMER_FREQUENCY as u64
+}
+
+/// Rounds up, so that waiting for the returned number of ticks never takes
+/// less than `ms` milliseconds. Saturates instead of overflowing.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let frequency = TIMER_FREQUENCY as u64;
+    // whole seconds first, so that `ms` can't overflow the multiplication
+    let remainder = ((ms % 1000) * frequency + 999) / 1000;
+    (ms / 1000)
+        .saturating_mul(frequency)
+        .saturating_add(remainder)
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let start = ticks();
+    while ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    assert!(ticks() > start);
+}
+
+#[test_case]
+fn test_tick_conversion() {
+    assert_eq!(ticks_to_ms(ms_to_ticks(250)), 250);
+    assert!(ms_to_ticks(1) >= 1);
+}
+
+#[test_case]
+fn test_tick_conversion_saturates() {
+    assert!(ms_to_ticks(u64::MAX) >= u64::MAX / 1000);
+    assert_eq!(ms_to_ticks(1001), ms_to_ticks(1000) + ms_to_ticks(1));
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 
-use core::fmt;
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
@@ -227,6 +230,97 @@ impl fmt::Write for Writer {
     }
 }
 
+/// Row the crash screen writes to next, `usize::MAX` until it's shown.
+static CRASH_ROW: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// White on red output for fatal errors, written straight to the VGA buffer
+/// without taking the `WRITER` lock, which the crashed code may hold.
+pub struct CrashScreen {
+    buffer: &'static mut Buffer,
+    color_code: ColorCode,
+    column: usize,
+}
+
+impl CrashScreen {
+    /// Clears the screen to red the first time it's called. Later crash
+    /// screens append to it, so a panic while reporting a crash stays visible.
+    pub fn new() -> Self {
+        let mut screen = CrashScreen {
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            color_code: ColorCode::new(Color::White, Color::Red),
+            column: 0,
+        };
+        if CRASH_ROW.load(Ordering::Relaxed) == usize::MAX {
+            for row in 0..BUFFER_HEIGHT {
+                screen.clear_row(row);
+            }
+            CRASH_ROW.store(0, Ordering::Relaxed);
+        }
+        screen
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if byte == b'\n' {
+            self.new_line();
+            return;
+        }
+        if self.column >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        let ascii_character = match byte {
+            0x20..=0x7e => byte,
+            _ => 0xfe,
+        };
+        let row = CRASH_ROW.load(Ordering::Relaxed);
+        self.buffer.chars[row][self.column].write(ScreenChar {
+            ascii_character,
+            color_code: self.color_code,
+        });
+        self.column += 1;
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        let row = CRASH_ROW.load(Ordering::Relaxed);
+        if row + 1 < BUFFER_HEIGHT {
+            CRASH_ROW.store(row + 1, Ordering::Relaxed);
+            return;
+        }
+        for row in 1..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let char = self.buffer.chars[row][col].read();
+                self.buffer.chars[row - 1][col].write(char);
+            }
+        }
+        self.clear_row(BUFFER_HEIGHT - 1);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for col in 0..BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(blank);
+        }
+    }
+}
+
+impl Default for CrashScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for CrashScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte)
+        }
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     (FG: $fg:expr, BG: $bg:expr, SCREEN: $scr:expr, $($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*), $fg, $bg, $scr));
@@ -257,10 +351,12 @@ pub fn _print(args: fmt::Arguments, fg: Color, bg: Color, screen: usize) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
-    let mut writer = WRITER.lock();
-    writer.screen = screen;
-    writer.screens[screen].color_code = ColorCode::new(fg, bg);
+    // lock with interrupts disabled, so neither an interrupt handler nor a
+    // preempted thread can be left spinning on the lock
     interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.screen = screen;
+        writer.screens[screen].color_code = ColorCode::new(fg, bg);
         writer.write_fmt(args).unwrap();
     });
 }
@@ -287,3 +383,18 @@ fn test_println_many() {
         println!("test_println_many output");
     }
 }
+
+#[test_case]
+fn test_crash_screen() {
+    use core::fmt::Write;
+
+    let mut screen = CrashScreen::new();
+    writeln!(screen, "crash").unwrap();
+    let row = CRASH_ROW.load(Ordering::Relaxed) - 1;
+    let screen_char = screen.buffer.chars[row][0].read();
+    assert_eq!(screen_char.ascii_character, b'c');
+    assert_eq!(
+        screen_char.color_code,
+        ColorCode::new(Color::White, Color::Red)
+    );
+}
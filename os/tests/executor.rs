@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use os::task::{
+    executor::{Executor, INITIAL_QUEUE_CAPACITY},
+    join::JoinError,
+    yield_now, Priority, Task, TaskState,
+};
+use os::time;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_handle_yields_output() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        let handle = spawner.spawn(async { 6 * 7 });
+        assert_eq!(handle.await, Ok(42));
+    }));
+    executor.run_until_complete();
+}
+
+static COMPLETED: AtomicU32 = AtomicU32::new(0);
+
+#[test_case]
+fn tasks_spawn_tasks() {
+    COMPLETED.store(0, Ordering::SeqCst);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let handles = (0..10)
+            .map(|i| {
+                os::task::executor::spawn(async move {
+                    COMPLETED.fetch_add(1, Ordering::SeqCst);
+                    i
+                })
+            })
+            .collect::<alloc::vec::Vec<_>>();
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        assert_eq!(sum, 45);
+    }));
+    executor.run_until_complete();
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 10);
+}
+
+#[test_case]
+fn aborted_task_is_dropped() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::named("supervisor", async move {
+        let handle = spawner.spawn_named("forever", core::future::pending::<()>());
+        os::task::timer::sleep(5).await;
+        let info = spawner
+            .tasks()
+            .into_iter()
+            .find(|info| info.id == handle.id())
+            .expect("spawned task not listed");
+        assert_eq!(info.name, "forever");
+        assert_eq!(info.state, TaskState::Pending);
+        assert!(spawner.abort(handle.id()));
+        assert_eq!(handle.await, Err(JoinError));
+        assert_eq!(spawner.tasks().len(), 1);
+    }));
+    executor.run_until_complete();
+}
+
+#[test_case]
+fn queued_task_can_be_aborted() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        // not polled yet, the executor only picks it up in its next round
+        let handle = spawner.spawn_named("queued", core::future::pending::<()>());
+        assert!(spawner.tasks().iter().any(|info| info.id == handle.id()));
+        assert!(spawner.abort(handle.id()));
+        assert_eq!(handle.await, Err(JoinError));
+        assert_eq!(spawner.tasks().len(), 1);
+    }));
+    executor.run_until_complete();
+}
+
+static WOKEN: AtomicU32 = AtomicU32::new(0);
+
+#[test_case]
+fn thousands_of_tasks_wake_together() {
+    const TASKS: u32 = 3000;
+
+    WOKEN.store(0, Ordering::SeqCst);
+    let mut executor = Executor::new();
+    // all sleep until the same tick, so one timer interrupt wakes all of them
+    let deadline = time::ticks() + time::ms_to_ticks(500);
+    for _ in 0..TASKS {
+        executor.spawn(Task::new(async move {
+            os::task::timer::sleep_until(deadline).await;
+            WOKEN.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    assert!(time::ticks() < deadline, "spawning took too long");
+    assert!(executor.queue_capacity() >= TASKS as usize);
+    assert!(executor.queue_capacity() > INITIAL_QUEUE_CAPACITY);
+    executor.run_until_complete();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), TASKS);
+}
+
+static ORDER: spin::Mutex<alloc::vec::Vec<Priority>> = spin::Mutex::new(alloc::vec::Vec::new());
+
+#[test_case]
+fn higher_priority_runs_first() {
+    ORDER.lock().clear();
+    let mut executor = Executor::new();
+    for priority in [
+        Priority::Background,
+        Priority::Normal,
+        Priority::Interactive,
+    ] {
+        executor
+            .spawn(Task::new(async move { ORDER.lock().push(priority) }).with_priority(priority));
+    }
+    executor.run_until_complete();
+    assert_eq!(
+        *ORDER.lock(),
+        [
+            Priority::Interactive,
+            Priority::Normal,
+            Priority::Background
+        ]
+    );
+}
+
+static DONE: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn busy_task_does_not_starve_others() {
+    DONE.store(false, Ordering::SeqCst);
+    let mut executor = Executor::new();
+    for _ in 0..4 {
+        executor.spawn(Task::new(async {
+            while !DONE.load(Ordering::SeqCst) {
+                yield_now().await;
+            }
+        }));
+    }
+    executor.spawn(
+        Task::new(async {
+            os::task::timer::sleep(10).await;
+            DONE.store(true, Ordering::SeqCst);
+        })
+        .with_priority(Priority::Background),
+    );
+    executor.run_until_complete();
+}
+
+#[test_case]
+fn cpu_time_is_attributed_to_tasks() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let idle_before = os::task::stats::cpu_stats().idle_ticks;
+    executor.spawn(Task::named("spinner", async move {
+        let start = os::time::ticks();
+        while os::time::ticks() < start + 20 {
+            core::hint::spin_loop();
+        }
+        let info = spawner
+            .tasks()
+            .into_iter()
+            .find(|info| info.name == "spinner")
+            .unwrap();
+        assert!(info.cpu_ticks >= 15);
+        os::task::timer::sleep(20).await;
+    }));
+    executor.run_until_complete();
+    assert!(os::task::stats::cpu_stats().idle_ticks - idle_before >= 10);
+}
@@ -63,3 +63,164 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+use os::allocator::heap_size;
+
+/// Cycles spent on allocating and freeing boxes of mixed sizes, printed to
+/// compare the allocators selected with the `alloc-*` features.
+#[test_case]
+fn mixed_sizes_benchmark() {
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut boxes = Vec::with_capacity(256);
+    for round in 0..16 {
+        for i in 0..256usize {
+            boxes.push(alloc::vec![0u8; 8 + (i * 97 + round) % 3000]);
+        }
+        // free a third first to leave holes
+        boxes.retain(|vec| vec.len() % 3 != 0);
+        boxes.clear();
+    }
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() } - start;
+    os::serial_print!(
+        "[{} allocator: {} kcycles] ",
+        heap_stats().allocator,
+        cycles / 1000
+    );
+}
+
+#[test_case]
+fn heap_grows() {
+    let mut vecs = Vec::new();
+    for i in 0..4 {
+        vecs.push(alloc::vec![i as u8; 1024 * 1024]);
+    }
+    assert!(heap_size() >= 4 * 1024 * 1024);
+    for (i, vec) in vecs.iter().enumerate() {
+        assert!(vec.iter().all(|&byte| byte == i as u8));
+    }
+}
+
+use os::allocator::heap_stats;
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = heap_stats();
+    let value = Box::new([0u8; 100]);
+    let during = heap_stats();
+    if !cfg!(feature = "heap-debug") {
+        // with redzones, the allocator sees a larger size
+        assert_eq!(during.alloc.allocated, before.alloc.allocated + 100);
+    }
+    assert_eq!(during.alloc.live(), before.alloc.live() + 1);
+    assert!(during.alloc.peak >= during.alloc.allocated);
+    drop(value);
+    let after = heap_stats();
+    assert_eq!(after.alloc.allocated, before.alloc.allocated);
+    assert_eq!(after.alloc.live(), before.alloc.live());
+}
+
+#[test_case]
+fn stats_track_size_classes() {
+    let before = match heap_stats().blocks {
+        Some(blocks) => blocks,
+        None => return, // not the fixed-size block allocator
+    };
+    if cfg!(feature = "heap-debug") {
+        return; // redzones move the allocation to a larger class
+    }
+    let value = Box::new([0u8; 100]);
+    // 100 bytes go to the 128 byte blocks
+    let during = heap_stats().blocks.unwrap();
+    assert_eq!(
+        during.size_classes[4].in_use,
+        before.size_classes[4].in_use + 1
+    );
+    drop(value);
+    let after = heap_stats().blocks.unwrap();
+    assert_eq!(after.size_classes[4].in_use, before.size_classes[4].in_use);
+}
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use os::allocator::{
+    linked_list::{FitStrategy, LinkedListAllocator},
+    Locked,
+};
+
+const ARENA_SIZE: usize = 16 * 1024;
+
+/// A `LinkedListAllocator` managing its own region of the kernel heap.
+fn arena(strategy: FitStrategy) -> Locked<LinkedListAllocator> {
+    let layout = Layout::from_size_align(ARENA_SIZE, 16).unwrap();
+    let arena = unsafe { alloc::alloc::alloc(layout) };
+    assert!(!arena.is_null());
+    let allocator = Locked::new(LinkedListAllocator::with_strategy(strategy));
+    unsafe { allocator.lock().init(arena as usize, ARENA_SIZE) };
+    allocator
+}
+
+#[test_case]
+fn linked_list_coalesces() {
+    let allocator = arena(FitStrategy::FirstFit);
+    for round in 0..10 {
+        let layouts = (0..32)
+            .map(|i| Layout::from_size_align(16 + (i * 37 + round) % 200, 8).unwrap())
+            .collect::<Vec<_>>();
+        let ptrs = layouts
+            .iter()
+            .map(|&layout| unsafe { allocator.alloc(layout) })
+            .collect::<Vec<_>>();
+        assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+        // free every other allocation first so that the holes are merged
+        // from both sides
+        for i in (0..32).step_by(2).chain((1..32).step_by(2)) {
+            unsafe { allocator.dealloc(ptrs[i], layouts[i]) };
+        }
+        let regions = allocator.lock().free_regions();
+        assert_eq!(regions.count, 1);
+        assert_eq!(regions.largest, ARENA_SIZE);
+    }
+}
+
+#[test_case]
+fn linked_list_best_fit() {
+    let allocator = arena(FitStrategy::BestFit);
+    let large = Layout::from_size_align(512, 8).unwrap();
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let a = unsafe { allocator.alloc(large) };
+    let b = unsafe { allocator.alloc(small) };
+    let c = unsafe { allocator.alloc(small) };
+    let d = unsafe { allocator.alloc(small) };
+    unsafe {
+        allocator.dealloc(a, large);
+        allocator.dealloc(c, small);
+    }
+    // first fit would split the large hole at the start
+    let e = unsafe { allocator.alloc(small) };
+    assert_eq!(e, c);
+    unsafe {
+        allocator.dealloc(b, small);
+        allocator.dealloc(d, small);
+        allocator.dealloc(e, small);
+    }
+    assert_eq!(allocator.lock().free_regions().count, 1);
+}
+
+#[test_case]
+fn linked_list_realloc_in_place() {
+    let allocator = arena(FitStrategy::FirstFit);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    unsafe { ptr.write_bytes(0xab, 64) };
+    let grown = unsafe { allocator.realloc(ptr, layout, 1024) };
+    assert_eq!(grown, ptr);
+    assert!(unsafe { core::slice::from_raw_parts(grown, 64) }
+        .iter()
+        .all(|&byte| byte == 0xab));
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let shrunk = unsafe { allocator.realloc(grown, layout, 128) };
+    assert_eq!(shrunk, ptr);
+    unsafe { allocator.dealloc(shrunk, Layout::from_size_align(128, 8).unwrap()) };
+    let regions = allocator.lock().free_regions();
+    assert_eq!(regions.count, 1);
+    assert_eq!(allocator.lock().stats().allocated, 0);
+}
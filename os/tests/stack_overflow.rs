@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{fmt::Write, panic::PanicInfo};
+use os::{exit_qemu, serial_print, serial_println, thread, QemuExitCode};
+use volatile::Volatile;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::thread_stack_overflow...\t");
+    os::init(boot_info);
+    let id = thread::spawn("overflow", stack_overflow).unwrap();
+    thread::join(id);
+    serial_println!("[test did not overflow]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+/// Keeps the start of the panic message.
+struct Message {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buf: [0; 128],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+    if message.contains("stack overflow in thread overflow") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::stream::StreamExt;
+use os::task::{
+    executor::{self, Executor},
+    sync::{mpsc, oneshot, Mutex, Notify},
+    timer::sleep,
+    Task,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+#[test_case]
+fn mutex_held_across_await() {
+    let mut executor = Executor::new();
+    let counter = Arc::new(Mutex::new(0));
+    for _ in 0..5 {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move {
+            let mut guard = counter.lock().await;
+            let value = *guard;
+            sleep(1).await;
+            *guard = value + 1;
+        }));
+    }
+    executor.run_until_complete();
+    assert_eq!(*counter.try_lock().unwrap(), 5);
+}
+
+#[test_case]
+fn bounded_channel_applies_backpressure() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let (tx, mut rx) = mpsc::channel(2);
+        let producer = executor::spawn(async move {
+            for i in 0..10 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        sleep(5).await;
+        // the producer is stuck until we make room
+        assert!(!producer.is_finished());
+        let mut received = Vec::new();
+        while let Some(value) = rx.recv().await {
+            received.push(value);
+        }
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        producer.await.unwrap();
+    }));
+    executor.run_until_complete();
+}
+
+#[test_case]
+fn unbounded_channel_is_a_stream() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for i in 0..100 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        assert_eq!(rx.collect::<Vec<u32>>().await.len(), 100);
+    }));
+    executor.run_until_complete();
+}
+
+#[test_case]
+fn oneshot_delivers_or_reports_drop() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let (tx, rx) = oneshot::channel();
+        executor::spawn(async move {
+            sleep(2).await;
+            tx.send("pong").unwrap();
+        });
+        assert_eq!(rx.await, Ok("pong"));
+
+        let (tx, rx) = oneshot::channel::<u8>();
+        drop(tx);
+        assert_eq!(rx.await, Err(oneshot::RecvError));
+    }));
+    executor.run_until_complete();
+}
+
+static NOTIFY: Notify = Notify::new();
+
+#[test_case]
+fn notify_wakes_waiter() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let waiter = executor::spawn(async {
+            NOTIFY.notified().await;
+        });
+        sleep(2).await;
+        assert!(!waiter.is_finished());
+        NOTIFY.notify_one();
+        waiter.await.unwrap();
+
+        // a permit stored without waiters is consumed by the next wait
+        NOTIFY.notify_one();
+        NOTIFY.notified().await;
+    }));
+    executor.run_until_complete();
+}
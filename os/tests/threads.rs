@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use os::{thread, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static COUNTER_A: AtomicU64 = AtomicU64::new(0);
+static COUNTER_B: AtomicU64 = AtomicU64::new(0);
+
+fn spin_until_stopped(counter: &'static AtomicU64) {
+    while !STOP.load(Ordering::SeqCst) {
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn cpu_bound_threads_both_progress() {
+    STOP.store(false, Ordering::SeqCst);
+    let a = thread::spawn("spin-a", || spin_until_stopped(&COUNTER_A)).unwrap();
+    let b = thread::spawn("spin-b", || spin_until_stopped(&COUNTER_B)).unwrap();
+
+    // neither thread ever yields, so the boot thread only gets the CPU back
+    // through preemption
+    let start = time::ticks();
+    while time::ticks() < start + 10 * thread::TIME_SLICE_TICKS {
+        core::hint::spin_loop();
+    }
+    let (a_before, b_before) = (
+        COUNTER_A.load(Ordering::SeqCst),
+        COUNTER_B.load(Ordering::SeqCst),
+    );
+    assert!(a_before > 0);
+    assert!(b_before > 0);
+
+    let start = time::ticks();
+    while time::ticks() < start + 10 * thread::TIME_SLICE_TICKS {
+        core::hint::spin_loop();
+    }
+    assert!(COUNTER_A.load(Ordering::SeqCst) > a_before);
+    assert!(COUNTER_B.load(Ordering::SeqCst) > b_before);
+
+    STOP.store(true, Ordering::SeqCst);
+    thread::join(a);
+    thread::join(b);
+    assert!(!thread::is_alive(a));
+    assert_eq!(thread::threads().len(), 1);
+}
+
+#[test_case]
+fn threads_can_yield_and_exit() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let id = thread::spawn("yielder", || {
+        for _ in 0..10 {
+            thread::yield_now();
+        }
+        DONE.store(true, Ordering::SeqCst);
+    })
+    .unwrap();
+    assert_ne!(id, thread::current());
+    thread::join(id);
+    assert!(DONE.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn threads_live_in_a_slab_cache() {
+    use os::allocator::slab;
+
+    let in_use = || {
+        slab::cache_stats()
+            .into_iter()
+            .find(|cache| cache.name == "thread")
+            .expect("no thread cache")
+            .in_use
+    };
+    thread::reap();
+    let before = in_use();
+    assert!(before >= 1, "the boot thread is always alive");
+    let id = thread::spawn("short", thread::yield_now).unwrap();
+    assert_eq!(in_use(), before + 1);
+    thread::join(id);
+    thread::reap();
+    assert_eq!(in_use(), before);
+}
+
+#[test_case]
+fn spawn_fails_once_every_thread_is_in_use() {
+    use alloc::vec::Vec;
+
+    static RELEASE: AtomicBool = AtomicBool::new(false);
+    let wait = || {
+        while !RELEASE.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+    };
+    RELEASE.store(false, Ordering::SeqCst);
+    let mut ids = Vec::new();
+    while let Some(id) = thread::spawn("waiter", wait) {
+        ids.push(id);
+    }
+    assert_eq!(thread::threads().len(), thread::MAX_THREADS);
+    assert!(!ids.is_empty());
+
+    RELEASE.store(true, Ordering::SeqCst);
+    for id in ids {
+        thread::join(id);
+    }
+    thread::reap();
+    assert!(thread::spawn("after", || {}).map(thread::join).is_some());
+}
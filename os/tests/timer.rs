@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::stream::StreamExt;
+use os::task::{
+    executor::Executor,
+    timer::{interval, sleep},
+    Task,
+};
+use os::time;
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+#[test_case]
+fn sleep_waits_at_least_duration() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = time::ticks();
+        sleep(20).await;
+        assert!(time::ticks() - start >= time::ms_to_ticks(20));
+    }));
+    executor.run_until_complete();
+}
+
+static WAKE_ORDER: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+#[test_case]
+fn sleepers_wake_in_deadline_order() {
+    WAKE_ORDER.lock().clear();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        sleep(60).await;
+        WAKE_ORDER.lock().push(2);
+    }));
+    executor.spawn(Task::new(async {
+        sleep(10).await;
+        WAKE_ORDER.lock().push(1);
+    }));
+    executor.run_until_complete();
+    assert_eq!(*WAKE_ORDER.lock(), [1, 2]);
+}
+
+#[test_case]
+fn interval_yields_periodically() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = time::ticks();
+        let ticks = interval(5).take(3).collect::<Vec<_>>().await;
+        assert_eq!(ticks.len(), 3);
+        assert!(ticks.windows(2).all(|w| w[0] < w[1]));
+        assert!(ticks[2] - start >= time::ms_to_ticks(15));
+    }));
+    executor.run_until_complete();
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{
+    elf::ElfError,
+    memory::vma,
+    process::{self, SpawnError},
+    task::{executor::Executor, Task},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+#[test_case]
+fn hello_runs_in_ring_3() {
+    let hello = process::exec("hello").expect("failed to start hello");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        // `hello` exits with the privilege level it ran at
+        assert_eq!(hello.wait().await, 3);
+    }));
+    executor.run_until_complete();
+}
+
+#[test_case]
+fn echo_reads_foreground_keys() {
+    let echo = process::exec("echo").expect("failed to start echo");
+    process::set_foreground(Some(&echo));
+    for key in "hi\n".chars() {
+        assert!(process::send_key(key));
+    }
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        assert_eq!(echo.wait().await, 0);
+    }));
+    executor.run_until_complete();
+    assert!(!process::send_key('x'));
+}
+
+#[test_case]
+fn unknown_program() {
+    assert!(matches!(
+        process::exec("does-not-exist"),
+        Err(SpawnError::UnknownProgram)
+    ));
+}
+
+#[test_case]
+fn rejects_invalid_elf() {
+    assert!(matches!(
+        process::spawn("garbage", &[0; 64]),
+        Err(SpawnError::InvalidElf(ElfError::BadMagic))
+    ));
+}
+
+#[test_case]
+fn processes_have_separate_address_spaces() {
+    // both are loaded at the same addresses
+    let first = process::exec("hello").expect("failed to start hello");
+    let second = process::exec("hello").expect("failed to start hello");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        assert_eq!(first.wait().await, 3);
+        assert_eq!(second.wait().await, 3);
+    }));
+    executor.run_until_complete();
+}
+
+#[test_case]
+fn faulting_process_is_killed() {
+    let faults = vma::demand_faults();
+    let fault = process::exec("fault").expect("failed to start fault");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        assert_eq!(fault.wait().await, process::EXIT_FAULT);
+        // the rest of the system keeps running
+        let hello = process::exec("hello").expect("failed to start hello");
+        assert_eq!(hello.wait().await, 3);
+    }));
+    executor.run_until_complete();
+    // at least the stack page was mapped on demand
+    assert!(vma::demand_faults() > faults);
+}
+
+#[test_case]
+fn segments_share_boundary_pages() {
+    let shared = process::exec("shared").expect("failed to load segments sharing a page");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        assert_eq!(shared.wait().await, 42);
+    }));
+    executor.run_until_complete();
+}
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Cargo runner for the kernel and its tests.
+#
+# Writes the kernel's symbol table into its `.ksyms` section, which
+# `backtrace.rs` reserves, then boots it with `bootimage runner`. Set NM and
+# OBJCOPY to use other binutils than the ones on the PATH.
+set -e
+
+# size of the `.ksyms` section, see `backtrace.rs`
+KSYMS_SIZE=1048576
+NM="${NM:-nm}"
+OBJCOPY="${OBJCOPY:-objcopy}"
+
+kernel="$1"
+symbols="$(mktemp)"
+trap 'rm -f "$symbols"' EXIT
+
+# one "ADDRESS NAME" line per function, without the Rust symbol hashes
+"$NM" --defined-only --numeric-sort --demangle "$kernel" \
+    | grep -E '^[0-9a-f]+ [tTwW] ' \
+    | sed -E 's/^([0-9a-f]+) . /\1 /; s/::h[0-9a-f]{16}$//' \
+    > "$symbols"
+
+# the padding must leave at least one NUL to end the table
+if [ "$(wc -c < "$symbols")" -ge "$KSYMS_SIZE" ]; then
+    echo "runner.sh: symbol table doesn't fit into .ksyms, raise KSYMS_SIZE" >&2
+    exit 1
+fi
+truncate -s "$KSYMS_SIZE" "$symbols"
+"$OBJCOPY" --update-section .ksyms="$symbols" "$kernel"
+
+exec bootimage runner "$@"